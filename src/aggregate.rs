@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::BufRead;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::util::{summarize_samples, LatencyMeasurement};
+
+/// One latency sample as a line of JSON, e.g. as printed by `client --stream`. Only the latency
+/// is required, so samples from other tools can be read too.
+#[derive(Deserialize)]
+struct SampleLine {
+    latency_ns: u64,
+    #[serde(default)]
+    payload_size: u64,
+    #[serde(default)]
+    name: Option<String>,
+    /// When the sample was taken, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    ts: Option<u64>,
+    /// `Some(None)` for an explicit `null`, which the client writes when no response arrived.
+    #[serde(default, deserialize_with = "present")]
+    status: Option<Option<u16>>,
+    /// Whether the client counted the sample as a success, which overrides the status.
+    #[serde(default)]
+    ok: Option<bool>,
+    /// A warmup is skipped, as the client leaves it out of its own summary.
+    #[serde(default)]
+    warmup: bool,
+}
+
+impl SampleLine {
+    /// Failed when the sample says so. Without an `ok`, a sample that got no response or one
+    /// outside 2xx fails, as it would in the client without `--expect-status`.
+    fn failed(&self) -> bool {
+        match (self.ok, self.status) {
+            (Some(ok), _) => !ok,
+            (None, Some(Some(status))) => !(200..300).contains(&status),
+            (None, Some(None)) => true,
+            (None, None) => false,
+        }
+    }
+}
+
+/// Tells a field given as `null` apart from one left out, which `#[serde(default)]` makes `None`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Samples sharing a name and payload size, from however many files or machines.
+#[derive(Default)]
+struct Group {
+    samples: Vec<Duration>,
+    failures: usize,
+    first_ts: Option<u64>,
+    last_ts: Option<u64>,
+}
+
+/// The combined statistics of one name and payload size.
+pub struct Aggregated {
+    pub name: String,
+    pub payload_size: u64,
+    pub latency: LatencyMeasurement,
+}
+
+/// Series name for samples that don't give one, matching the client's default.
+const DEFAULT_NAME: &str = "Request";
+
+/// Reads samples, one JSON object per line, and summarises each name and payload size. Blank
+/// lines and warmups are skipped. A failed sample, see `SampleLine::failed`, is counted as a failure rather
+/// than a latency, as the client does. The request rate is taken from the span of the timestamps
+/// when the samples have them, and is zero when they don't.
+pub fn aggregate(input: impl BufRead) -> Result<Vec<Aggregated>, Box<dyn Error>> {
+    let mut groups: BTreeMap<(String, u64), Group> = BTreeMap::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let sample: SampleLine =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {e}", index + 1))?;
+        if sample.warmup {
+            continue;
+        }
+
+        let failed = sample.failed();
+        let name = sample.name.unwrap_or_else(|| DEFAULT_NAME.to_string());
+        let group = groups.entry((name, sample.payload_size)).or_default();
+        if failed {
+            group.failures += 1;
+        } else {
+            group.samples.push(Duration::from_nanos(sample.latency_ns));
+        }
+        if let Some(ts) = sample.ts {
+            group.first_ts = Some(group.first_ts.map_or(ts, |first| first.min(ts)));
+            group.last_ts = Some(group.last_ts.map_or(ts, |last| last.max(ts)));
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|((name, payload_size), group)| {
+            let span = match (group.first_ts, group.last_ts) {
+                (Some(first), Some(last)) => Duration::from_millis(last - first),
+                _ => Duration::ZERO,
+            };
+            Aggregated {
+                name,
+                payload_size,
+                latency: summarize_samples(&group.samples, group.failures, span),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_groups_samples() {
+        let input = concat!(
+            r#"{"latency_ns":1000,"payload_size":1024,"name":"a","ts":1000}"#,
+            "\n\n",
+            r#"{"latency_ns":3000,"payload_size":1024,"name":"a","ts":2000}"#,
+            "\n",
+            r#"{"latency_ns":9000,"payload_size":1024,"name":"a","status":503}"#,
+            "\n",
+            r#"{"ts":5,"latency_ns":2000,"status":200}"#,
+            "\n",
+        );
+        let groups = aggregate(input.as_bytes()).unwrap();
+        assert_eq!(groups.len(), 2);
+
+        // Ordered by name, then size
+        assert_eq!(
+            (groups[0].name.as_str(), groups[0].payload_size),
+            ("Request", 0)
+        );
+        assert_eq!(groups[0].latency.samples, [Duration::from_nanos(2000)]);
+
+        let a = &groups[1];
+        assert_eq!((a.name.as_str(), a.payload_size), ("a", 1024));
+        assert_eq!(a.latency.latency, Duration::from_nanos(2000));
+        assert_eq!(a.latency.max, Duration::from_nanos(3000));
+        assert_eq!(a.latency.failures, 1);
+        assert_eq!(a.latency.iterations, 3);
+        // Three samples over the second between the first and last timestamps
+        assert_eq!(a.latency.requests_per_second, 3.0);
+    }
+
+    #[test]
+    fn test_aggregate_counts_failed_samples() {
+        let input = concat!(
+            r#"{"latency_ns":5000000000,"status":null}"#,
+            "\n",
+            r#"{"latency_ns":1000,"status":200,"ok":false}"#,
+            "\n",
+            r#"{"latency_ns":2000,"status":404,"ok":true}"#,
+            "\n",
+            r#"{"latency_ns":3000,"status":301}"#,
+            "\n",
+            r#"{"latency_ns":4000}"#,
+            "\n",
+        );
+        let groups = aggregate(input.as_bytes()).unwrap();
+        assert_eq!(groups.len(), 1);
+        let latency = &groups[0].latency;
+        assert_eq!(latency.failures, 3);
+        assert_eq!(
+            latency.samples,
+            [Duration::from_nanos(2000), Duration::from_nanos(4000)]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_skips_warmups() {
+        let input = concat!(
+            r#"{"latency_ns":9000,"ts":0,"ok":false,"warmup":true}"#,
+            "\n",
+            r#"{"latency_ns":1000,"ts":1000,"ok":true,"warmup":false}"#,
+            "\n",
+            r#"{"latency_ns":3000,"ts":2000,"ok":true,"warmup":false}"#,
+            "\n",
+        );
+        let groups = aggregate(input.as_bytes()).unwrap();
+        let latency = &groups[0].latency;
+        assert_eq!((latency.iterations, latency.failures), (2, 0));
+        assert_eq!(latency.max, Duration::from_nanos(3000));
+        // Nor does the warmup's timestamp stretch the span the rate is taken over
+        assert_eq!(latency.requests_per_second, 2.0);
+    }
+
+    #[test]
+    fn test_aggregate_reports_bad_line() {
+        let input = "{\"latency_ns\":1}\n{\"payload_size\":2}\n";
+        let error = aggregate(input.as_bytes()).err().unwrap();
+        assert!(error.to_string().starts_with("line 2:"), "{error}");
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::plot::Measurement;
+use crate::util::format_size;
+
+/// How one point of the current run compares with the same point of the baseline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointChange {
+    pub name: String,
+    pub payload_size: u64,
+    pub baseline: u64,
+    pub current: u64,
+    /// Latency change in percent, positive when the current run is slower.
+    pub change: f64,
+    /// The change is above the threshold.
+    pub regressed: bool,
+}
+
+/// Pairs the points of two saved runs by name and payload size and works out how much each
+/// point's latency changed. Points only one run has are left out, in the current run's order.
+/// A point regressed when it is more than `threshold` percent slower than the baseline.
+pub fn compare_runs(
+    baseline: &[Measurement],
+    current: &[Measurement],
+    threshold: f64,
+) -> Vec<PointChange> {
+    let baseline: HashMap<(&str, u64), u64> = baseline
+        .iter()
+        .map(|m| ((m.name.as_str(), m.payload_size), m.latency))
+        .collect();
+
+    current
+        .iter()
+        .filter_map(|m| {
+            let before = *baseline.get(&(m.name.as_str(), m.payload_size))?;
+            let change = if before == 0 {
+                0.0
+            } else {
+                (m.latency as f64 - before as f64) / before as f64 * 100.0
+            };
+            Some(PointChange {
+                name: m.name.clone(),
+                payload_size: m.payload_size,
+                baseline: before,
+                current: m.latency,
+                change,
+                regressed: change > threshold,
+            })
+        })
+        .collect()
+}
+
+/// Prints a row per compared point, marking the ones that regressed.
+pub fn print_comparison(changes: &[PointChange]) {
+    println!(
+        "{:<16} {:>10} {:>14} {:>14} {:>9}",
+        "Name", "Size", "Baseline", "Current", "Change"
+    );
+    for change in changes {
+        println!(
+            "{:<16} {:>10} {:>14} {:>14} {:>+8.1}%{}",
+            change.name,
+            format_size(change.payload_size),
+            format!("{:?}", Duration::from_nanos(change.baseline)),
+            format!("{:?}", Duration::from_nanos(change.current)),
+            change.change,
+            if change.regressed { "  REGRESSED" } else { "" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(name: &str, latency: u64, payload_size: u64) -> Measurement {
+        Measurement {
+            name: name.to_string(),
+            latency,
+            payload_size,
+            min_latency: None,
+            max_latency: None,
+            first_byte: None,
+        }
+    }
+
+    #[test]
+    fn test_compare_runs_aligns_points() {
+        let baseline = vec![
+            measurement("direct", 1000, 1024),
+            measurement("direct", 2000, 4096),
+            measurement("proxy", 3000, 1024),
+        ];
+        let current = vec![
+            measurement("direct", 1050, 1024),
+            measurement("direct", 2400, 4096),
+            measurement("direct", 9000, 16384),
+            measurement("proxy", 2700, 1024),
+        ];
+
+        let changes = compare_runs(&baseline, &current, 10.0);
+        assert_eq!(changes.len(), 3);
+
+        assert_eq!((changes[0].baseline, changes[0].current), (1000, 1050));
+        assert!((changes[0].change - 5.0).abs() < 1e-9);
+        assert!(!changes[0].regressed);
+
+        assert!((changes[1].change - 20.0).abs() < 1e-9);
+        assert!(changes[1].regressed);
+
+        assert_eq!(changes[2].name, "proxy");
+        assert!((changes[2].change + 10.0).abs() < 1e-9);
+        assert!(!changes[2].regressed);
+    }
+}
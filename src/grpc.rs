@@ -0,0 +1,407 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use h2::client::SendRequest;
+use http::header::{CONTENT_TYPE, TE};
+use http::{HeaderMap, Request, StatusCode, Uri};
+use percent_encoding::percent_decode;
+use reqwest::Url;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// The status codes of the gRPC spec, indexed by their value.
+const STATUS_NAMES: [&str; 17] = [
+    "OK",
+    "CANCELLED",
+    "UNKNOWN",
+    "INVALID_ARGUMENT",
+    "DEADLINE_EXCEEDED",
+    "NOT_FOUND",
+    "ALREADY_EXISTS",
+    "PERMISSION_DENIED",
+    "RESOURCE_EXHAUSTED",
+    "FAILED_PRECONDITION",
+    "ABORTED",
+    "OUT_OF_RANGE",
+    "UNIMPLEMENTED",
+    "INTERNAL",
+    "UNAVAILABLE",
+    "DATA_LOSS",
+    "UNAUTHENTICATED",
+];
+
+/// Makes unary gRPC calls over one HTTP/2 connection, sending the same request message each time.
+/// reqwest can't read HTTP/2 trailers, where a call's status usually is, so this uses h2 directly.
+pub struct GrpcClient {
+    addr: SocketAddr,
+    /// The connector and server name for `https` targets, which agree on HTTP/2 with ALPN.
+    /// `http` targets speak HTTP/2 from the start, as gRPC servers expect.
+    tls: Option<(tokio_native_tls::TlsConnector, String)>,
+    uri: Uri,
+    request: Bytes,
+    timeout: Duration,
+    /// Opened on the first call, and again after a call fails other than with a gRPC status, as
+    /// the connection may be what failed.
+    sender: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl GrpcClient {
+    /// Prepares calls of `method`, given as `package.Service/Method`, on the server at `url`,
+    /// connecting to `addr`. A call whose response doesn't arrive within `timeout` fails.
+    pub fn new(
+        url: &Url,
+        addr: SocketAddr,
+        method: &str,
+        message: &[u8],
+        accept_invalid_certs: bool,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let method = method.trim_start_matches('/');
+        let valid = method.split_once('/').is_some_and(|(service, name)| {
+            !service.is_empty() && !name.is_empty() && !name.contains('/')
+        });
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("method {method:?} isn't of the form package.Service/Method"),
+            ));
+        }
+
+        let host = url.host_str().unwrap_or("localhost");
+        let tls = match url.scheme() {
+            "http" => None,
+            "https" => {
+                let connector = native_tls::TlsConnector::builder()
+                    .danger_accept_invalid_certs(accept_invalid_certs)
+                    .request_alpns(&["h2"])
+                    .build()
+                    .map_err(io::Error::other)?;
+                Some((connector.into(), host.to_string()))
+            }
+            scheme => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't make gRPC calls to {scheme} URLs, only http or https"),
+                ))
+            }
+        };
+
+        let authority = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        let path = url.path().trim_end_matches('/');
+        let uri = format!("{}://{authority}{path}/{method}", url.scheme())
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(GrpcClient {
+            addr,
+            tls,
+            uri,
+            request: frame(message),
+            timeout,
+            sender: Mutex::new(None),
+        })
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Makes one call, failing on a transport error, a timeout or a status other than `OK`.
+    pub async fn call(&self) -> io::Result<()> {
+        let result = match tokio::time::timeout(self.timeout, self.try_call()).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no response in time",
+            )),
+        };
+        match result {
+            Ok(status) => status,
+            Err(e) => {
+                *self.sender.lock().unwrap() = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// The outer result is whether the call got an answer, and the inner one is the answer's
+    /// status, so only the first drops the connection.
+    async fn try_call(&self) -> io::Result<io::Result<()>> {
+        let sender = self.sender.lock().unwrap().clone();
+        let sender = match sender {
+            Some(sender) => sender,
+            None => {
+                let sender = self.connect().await?;
+                *self.sender.lock().unwrap() = Some(sender.clone());
+                sender
+            }
+        };
+        let mut sender = sender.ready().await.map_err(io::Error::other)?;
+
+        let request = Request::post(self.uri.clone())
+            .header(CONTENT_TYPE, "application/grpc")
+            .header(TE, "trailers")
+            .body(())
+            .map_err(io::Error::other)?;
+        let (response, mut stream) = sender
+            .send_request(request, false)
+            .map_err(io::Error::other)?;
+        stream
+            .send_data(self.request.clone(), true)
+            .map_err(io::Error::other)?;
+
+        let (parts, mut body) = response.await.map_err(io::Error::other)?.into_parts();
+        if parts.status != StatusCode::OK {
+            let status = parts.status.as_u16();
+            return Ok(Err(io::Error::other(format!(
+                "server returned status {status}"
+            ))));
+        }
+        // An error with no response message may be sent as headers alone
+        if let Some(status) = parse_status(&parts.headers) {
+            return Ok(status);
+        }
+
+        let mut received = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(io::Error::other)?;
+            let _ = body.flow_control().release_capacity(chunk.len());
+            received.extend_from_slice(&chunk);
+        }
+        let trailers = body
+            .trailers()
+            .await
+            .map_err(io::Error::other)?
+            .unwrap_or_default();
+
+        let status = parse_status(&trailers)
+            .unwrap_or_else(|| Err(invalid("response has no grpc-status")))
+            .and_then(|()| match count_messages(&received)? {
+                1 => Ok(()),
+                count => Err(invalid(format!(
+                    "unary call answered with {count} messages"
+                ))),
+            });
+        Ok(status)
+    }
+
+    async fn connect(&self) -> io::Result<SendRequest<Bytes>> {
+        let stream = TcpStream::connect(self.addr).await?;
+        stream.set_nodelay(true)?;
+        match &self.tls {
+            Some((connector, domain)) => {
+                let stream = connector
+                    .connect(domain, stream)
+                    .await
+                    .map_err(io::Error::other)?;
+                let alpn = stream
+                    .get_ref()
+                    .negotiated_alpn()
+                    .map_err(io::Error::other)?;
+                if alpn.as_deref() != Some(b"h2") {
+                    return Err(invalid("server didn't agree to HTTP/2"));
+                }
+                handshake(stream).await
+            }
+            None => handshake(stream).await,
+        }
+    }
+}
+
+/// Starts HTTP/2 on a connection, driving it on the current runtime until it closes.
+async fn handshake<T>(io: T) -> io::Result<SendRequest<Bytes>>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = h2::client::handshake(io).await.map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok(sender)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Frames a message as gRPC sends it, after a byte saying it isn't compressed and its length.
+fn frame(message: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(5 + message.len());
+    framed.put_u8(0);
+    framed.put_u32(message.len() as u32);
+    framed.put_slice(message);
+    framed.freeze()
+}
+
+/// Counts the framed messages in a response body, failing when the last one is cut short.
+fn count_messages(mut body: &[u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while !body.is_empty() {
+        let Some(header) = body.get(..5) else {
+            return Err(invalid("response message is cut short"));
+        };
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let Some(rest) = body.get(5 + len..) else {
+            return Err(invalid(format!(
+                "response message of {len} bytes is cut short"
+            )));
+        };
+        body = rest;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The call's status from its trailers, or from its headers when the server sent no body.
+/// `None` when they don't give one. A status other than `OK` is an error naming the code, with
+/// the server's `grpc-message` if it sent one.
+fn parse_status(headers: &HeaderMap) -> Option<io::Result<()>> {
+    let status = headers.get("grpc-status")?;
+    let Some(code) = status
+        .to_str()
+        .ok()
+        .and_then(|code| code.trim().parse::<usize>().ok())
+    else {
+        return Some(Err(invalid(format!("bad grpc-status: {status:?}"))));
+    };
+    if code == 0 {
+        return Some(Ok(()));
+    }
+
+    let name = STATUS_NAMES.get(code).copied().unwrap_or("UNKNOWN");
+    let message = headers
+        .get("grpc-message")
+        .map(|message| {
+            percent_decode(message.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .filter(|message| !message.is_empty());
+    let error = match message {
+        Some(message) => format!("grpc-status {code} {name}: {message}"),
+        None => format!("grpc-status {code} {name}"),
+    };
+    Some(Err(io::Error::other(error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::{HeaderValue, Response};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(&frame(b"abc")[..], b"\0\0\0\0\x03abc");
+        assert_eq!(&frame(b"")[..], b"\0\0\0\0\0");
+
+        assert_eq!(count_messages(b"").unwrap(), 0);
+        assert_eq!(count_messages(b"\0\0\0\0\x03abc\0\0\0\0\0").unwrap(), 2);
+        assert!(count_messages(b"\0\0\0\0\x04abc").is_err());
+        assert!(count_messages(b"\0\0\0").is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let mut headers = HeaderMap::new();
+        assert!(parse_status(&headers).is_none());
+
+        headers.insert("grpc-status", HeaderValue::from_static("0"));
+        assert!(parse_status(&headers).unwrap().is_ok());
+
+        headers.insert("grpc-status", HeaderValue::from_static("5"));
+        let error = parse_status(&headers).unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "grpc-status 5 NOT_FOUND");
+
+        headers.insert("grpc-message", HeaderValue::from_static("no%20such%20user"));
+        let error = parse_status(&headers).unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "grpc-status 5 NOT_FOUND: no such user");
+
+        headers.insert("grpc-status", HeaderValue::from_static("x"));
+        assert!(parse_status(&headers).unwrap().is_err());
+    }
+
+    /// Answers each call on one connection with the request message echoed back, alternating
+    /// between an `OK` status in the trailers and `NOT_FOUND` in the headers alone.
+    async fn serve(listener: TcpListener) -> Option<String> {
+        let (stream, _) = listener.accept().await.ok()?;
+        let mut connection = h2::server::handshake(stream).await.ok()?;
+        let mut path = None;
+        let mut calls = 0;
+        // Each call is answered on its own task, as the body only arrives while the
+        // connection is polled
+        while let Some(Ok((request, respond))) = connection.accept().await {
+            path = Some(request.uri().path().to_string());
+            tokio::spawn(answer(request, respond, calls % 2 == 0));
+            calls += 1;
+        }
+        path
+    }
+
+    async fn answer(
+        request: Request<h2::RecvStream>,
+        mut respond: h2::server::SendResponse<Bytes>,
+        ok: bool,
+    ) -> Option<()> {
+        let mut body = request.into_body();
+        let mut received = Vec::new();
+        while let Some(Ok(chunk)) = body.data().await {
+            received.extend_from_slice(&chunk);
+        }
+
+        let response = Response::builder().header(CONTENT_TYPE, "application/grpc");
+        if ok {
+            let mut stream = respond
+                .send_response(response.body(()).unwrap(), false)
+                .ok()?;
+            stream.send_data(received.into(), false).ok()?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            stream.send_trailers(trailers).ok()?;
+        } else {
+            let response = response.header("grpc-status", "5").body(()).unwrap();
+            respond.send_response(response, true).ok()?;
+        }
+        Some(())
+    }
+
+    #[test]
+    fn test_unary_calls() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(serve(listener));
+
+            let url = Url::parse(&format!("http://{addr}/")).unwrap();
+            let timeout = Duration::from_secs(5);
+            let client =
+                GrpcClient::new(&url, addr, "test.Echo/Say", b"hello", false, timeout).unwrap();
+            assert_eq!(
+                client.uri().to_string(),
+                format!("http://{addr}/test.Echo/Say")
+            );
+
+            client.call().await.unwrap();
+            let error = client.call().await.unwrap_err();
+            assert_eq!(error.to_string(), "grpc-status 5 NOT_FOUND");
+            // The connection is kept after an error status
+            client.call().await.unwrap();
+
+            drop(client);
+            assert_eq!(server.await.unwrap().as_deref(), Some("/test.Echo/Say"));
+        });
+
+        let url = Url::parse("http://localhost/").unwrap();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        assert!(GrpcClient::new(&url, addr, "Say", b"", false, Duration::ZERO).is_err());
+        let ftp = Url::parse("ftp://localhost/").unwrap();
+        assert!(GrpcClient::new(&ftp, addr, "a.B/C", b"", false, Duration::ZERO).is_err());
+    }
+}
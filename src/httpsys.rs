@@ -1,64 +1,92 @@
 use std::{
+    collections::HashMap,
     ffi::CStr,
     future::Future,
     os::raw::c_char,
     pin::Pin,
     sync::Arc,
-    sync::Mutex,
+    sync::{Condvar, Mutex},
     task::{Context, Poll, Waker},
 };
 use windows::{
     core::{Error, HRESULT, HSTRING, PCSTR},
     Win32::{
         Foundation::{
-            GetLastError, ERROR_INSUFFICIENT_BUFFER, ERROR_IO_INCOMPLETE, ERROR_IO_PENDING, HANDLE,
-            NO_ERROR, WIN32_ERROR,
+            ERROR_HANDLE_EOF, ERROR_INSUFFICIENT_BUFFER, ERROR_IO_PENDING, HANDLE, NO_ERROR,
+            WIN32_ERROR,
         },
         Networking::HttpServer::{
             HttpAddUrlToUrlGroup, HttpCloseRequestQueue, HttpCloseServerSession, HttpCloseUrlGroup,
             HttpCreateRequestQueue, HttpCreateServerSession, HttpCreateUrlGroup,
-            HttpDataChunkFromMemory, HttpInitialize, HttpReceiveHttpRequest, HttpSendHttpResponse,
-            HttpServerBindingProperty, HttpSetUrlGroupProperty, HttpTerminate, HTTPAPI_VERSION,
-            HTTP_BINDING_INFO, HTTP_DATA_CHUNK, HTTP_INITIALIZE_CONFIG, HTTP_INITIALIZE_SERVER,
-            HTTP_RECEIVE_HTTP_REQUEST_FLAGS, HTTP_REQUEST_V2, HTTP_RESPONSE_V2,
-            HTTP_SERVER_PROPERTY,
+            HttpDataChunkFromMemory, HttpInitialize, HttpReceiveHttpRequest,
+            HttpReceiveRequestEntityBody, HttpSendHttpResponse, HttpSendResponseEntityBody,
+            HttpServerBindingProperty, HttpSetUrlGroupProperty, HttpTerminate, HttpVerbCONNECT,
+            HttpVerbCOPY, HttpVerbDELETE, HttpVerbGET, HttpVerbHEAD, HttpVerbLOCK, HttpVerbMKCOL,
+            HttpVerbMOVE, HttpVerbOPTIONS, HttpVerbPOST, HttpVerbPROPFIND, HttpVerbPROPPATCH,
+            HttpVerbPUT, HttpVerbSEARCH, HttpVerbTRACE, HttpVerbTRACK, HttpVerbUNLOCK,
+            HTTPAPI_VERSION, HTTP_BINDING_INFO, HTTP_DATA_CHUNK, HTTP_INITIALIZE_CONFIG,
+            HTTP_INITIALIZE_SERVER, HTTP_RECEIVE_HTTP_REQUEST_FLAGS,
+            HTTP_REQUEST_FLAG_MORE_ENTITY_BODY_EXISTS, HTTP_REQUEST_V2, HTTP_RESPONSE_V2,
+            HTTP_SERVER_PROPERTY, HTTP_UNKNOWN_HEADER, HTTP_VERB,
         },
-        System::IO::{BindIoCompletionCallback, GetOverlappedResult, OVERLAPPED},
+        System::IO::{BindIoCompletionCallback, CancelIoEx, OVERLAPPED},
     },
 };
 
+/// Completes with the result of the IO started on `optr`. It parks until `private_callback`
+/// wakes it rather than polling the overlapped for completion, so waiting costs no CPU.
 struct OverlappedFuture {
-    handle: HANDLE,
-    overlapped: OVERLAPPED,
+    h: HANDLE,
+    optr: Arc<OverlappedObject>,
+    token: AwaitableToken,
 }
 
 impl OverlappedFuture {
-    pub fn new(handle: HANDLE, overlapped: OVERLAPPED) -> Self {
-        OverlappedFuture { handle, overlapped }
+    /// Waits for an IO on `h` that HTTP.SYS has accepted, i.e. that returned pending or
+    /// completed straight away, as either way a completion is posted to `private_callback`.
+    /// The callback is given a reference of its own here, which it releases once it has
+    /// recorded the result. That is the only reference handed over, so each IO is released
+    /// exactly once.
+    fn started(h: HANDLE, optr: &Arc<OverlappedObject>) -> Self {
+        let _ = Arc::into_raw(optr.clone());
+        let token = optr.o.as_obj.get_await_token();
+        OverlappedFuture {
+            h,
+            optr: optr.clone(),
+            token,
+        }
     }
 }
 
-impl std::future::Future for OverlappedFuture {
-    type Output = std::io::Result<()>;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut bytes_transferred = 0;
-        let overlapped_ptr = &self.overlapped as *const _ as *mut OVERLAPPED;
-
-        let result = unsafe {
-            GetOverlappedResult(self.handle, overlapped_ptr, &mut bytes_transferred, false)
-        };
+/// HTTP.SYS writes into or reads from the caller's buffers, e.g. the `Request` being received
+/// into, until the IO completes. A future dropped before then, as when a receive loses a
+/// `select!` at shutdown or `drain` aborts a worker, cancels the IO and blocks until the
+/// cancellation completes, so the buffers are never freed while HTTP.SYS still uses them.
+impl Drop for OverlappedFuture {
+    fn drop(&mut self) {
+        if self.token.is_completed() {
+            return;
+        }
+        // Fails only when the IO has already finished, whose completion is then on its way
+        let _ = unsafe { CancelIoEx(self.h, Some(self.optr.get())) };
+        self.token.wait();
+    }
+}
 
-        if result != false {
-            Poll::Ready(Ok(()))
-        } else {
-            let error = unsafe { GetLastError() };
-            if error == ERROR_IO_INCOMPLETE {
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            } else {
-                Poll::Ready(Err(std::io::Error::from_raw_os_error(error.0 as i32)))
+impl std::future::Future for OverlappedFuture {
+    type Output = Result<u32, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.token).poll(cx) {
+            Poll::Ready(()) => {
+                let err = self.optr.get_ec();
+                if err == Error::OK {
+                    Poll::Ready(Ok(self.optr.get_len()))
+                } else {
+                    Poll::Ready(Err(err))
+                }
             }
+            Poll::Pending => Poll::Pending,
         }
     }
 }
@@ -76,16 +104,18 @@ unsafe extern "system" fn private_callback(
     let e = Error::from(WIN32_ERROR(dwerrorcode));
     if e.code().is_err() {}
 
-    let wrap_ptr: *mut OverlappedWrap = lpoverlapped as *mut OverlappedWrap;
-    let _wrap = Arc::from_raw(wrap_ptr);
-    let wrap: &mut OverlappedWrap = &mut *wrap_ptr;
+    // The OVERLAPPED starts the object, so this is the pointer `OverlappedFuture::started`
+    // handed over, and the reference it holds is released when this returns
+    let optr = Arc::from_raw(lpoverlapped as *const OverlappedObject);
+    let wrap: &mut OverlappedWrap = &mut *(lpoverlapped as *mut OverlappedWrap);
 
     if dwerrorcode != 0x80000005 && e.code().is_err() {
         wrap.err = e;
-    } else {
-        wrap.len = dwnumberofbytestransfered;
     }
+    // On ERROR_INSUFFICIENT_BUFFER this is the buffer size the request needs
+    wrap.len = dwnumberofbytestransfered;
     wrap.as_obj.wake();
+    drop(optr);
 }
 
 #[derive(Debug)]
@@ -96,10 +126,12 @@ struct SharedState {
 
 pub struct AsyncWaitObject {
     shared_state: Arc<Mutex<SharedState>>,
+    done: Arc<Condvar>,
 }
 
 pub struct AwaitableToken {
     shared_state: Arc<Mutex<SharedState>>,
+    done: Arc<Condvar>,
 }
 
 impl Default for AsyncWaitObject {
@@ -115,6 +147,7 @@ impl AsyncWaitObject {
                 completed: false,
                 waker: None,
             })),
+            done: Arc::new(Condvar::new()),
         }
     }
 
@@ -124,6 +157,7 @@ impl AsyncWaitObject {
         if let Some(waker) = shared_state.waker.take() {
             waker.wake()
         }
+        self.done.notify_all();
     }
 
     pub fn reset(&mut self) {
@@ -136,10 +170,26 @@ impl AsyncWaitObject {
     pub fn get_await_token(&self) -> AwaitableToken {
         AwaitableToken {
             shared_state: self.shared_state.clone(),
+            done: self.done.clone(),
         }
     }
 }
 
+impl AwaitableToken {
+    pub fn is_completed(&self) -> bool {
+        self.shared_state.lock().unwrap().completed
+    }
+
+    /// Blocks the thread until woken, for when the token can no longer be awaited.
+    pub fn wait(&self) {
+        let shared_state = self.shared_state.lock().unwrap();
+        let _completed = self
+            .done
+            .wait_while(shared_state, |state| !state.completed)
+            .unwrap();
+    }
+}
+
 impl Future for AwaitableToken {
     type Output = ();
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
@@ -181,10 +231,25 @@ impl OverlappedWrap {
     }
 }
 
+/// Kept in an `Arc` shared by the future awaiting an IO and the completion callback. It is
+/// `repr(C)` so that the OVERLAPPED passed to HTTP.SYS is at the start of the object, which is
+/// how the callback finds the object again.
+#[repr(C)]
 pub struct OverlappedObject {
     o: OverlappedWrap,
 }
 
+/// Overlapped objects not yet dropped, so tests can check every IO releases its own.
+#[cfg(test)]
+pub static LIVE_OVERLAPPED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+impl Drop for OverlappedObject {
+    fn drop(&mut self) {
+        LIVE_OVERLAPPED.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
 impl Default for OverlappedObject {
     fn default() -> Self {
         Self::new()
@@ -193,6 +258,8 @@ impl Default for OverlappedObject {
 
 impl OverlappedObject {
     pub fn new() -> Self {
+        #[cfg(test)]
+        LIVE_OVERLAPPED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
         OverlappedObject {
             o: OverlappedWrap::new(),
         }
@@ -210,10 +277,6 @@ impl OverlappedObject {
         ow_cast_ptr
     }
 
-    pub async fn wait(&self) {
-        self.o.as_obj.get_await_token().await;
-    }
-
     pub fn get_ec(&self) -> Error {
         self.o.err.clone()
     }
@@ -343,33 +406,103 @@ impl Drop for UrlGroup {
     }
 }
 
-#[repr(C)]
+/// Receive buffer for a request. HTTP.SYS writes an `HTTP_REQUEST_V2` at the front followed by
+/// the strings and headers it points to. The buffer is kept as `u64` words so the header is
+/// suitably aligned, and grows when a request doesn't fit.
 pub struct Request {
-    raw: HTTP_REQUEST_V2,
-    buff: [u8; 1024 * 4],
+    buff: Vec<u64>,
 }
 
+const DEFAULT_REQUEST_BUFFER_SIZE: usize = std::mem::size_of::<HTTP_REQUEST_V2>() + 1024 * 4;
+
 impl Default for Request {
     fn default() -> Request {
-        Request {
-            raw: HTTP_REQUEST_V2::default(),
-            buff: [0; 1024 * 4],
-        }
+        let mut request = Request { buff: Vec::new() };
+        request.grow(DEFAULT_REQUEST_BUFFER_SIZE);
+        request
     }
 }
 
 impl Request {
     pub fn raw(&mut self) -> &mut HTTP_REQUEST_V2 {
-        &mut self.raw
+        unsafe { &mut *(self.buff.as_mut_ptr() as *mut HTTP_REQUEST_V2) }
+    }
+
+    fn header(&self) -> &HTTP_REQUEST_V2 {
+        unsafe { &*(self.buff.as_ptr() as *const HTTP_REQUEST_V2) }
     }
 
-    pub fn size() -> u32 {
-        std::mem::size_of::<Request>() as u32
+    pub fn id(&self) -> u64 {
+        self.header().Base.RequestId
+    }
+
+    pub fn url_context(&self) -> u64 {
+        self.header().Base.UrlContext
+    }
+
+    pub fn size(&self) -> u32 {
+        (self.buff.len() * std::mem::size_of::<u64>()) as u32
+    }
+
+    /// Resizes the buffer to hold at least `required` bytes, at least doubling it so repeated
+    /// retries converge quickly. The contents are zeroed.
+    fn grow(&mut self, required: usize) {
+        let current = self.buff.len() * std::mem::size_of::<u64>();
+        let bytes = required
+            .max(current * 2)
+            .max(std::mem::size_of::<HTTP_REQUEST_V2>());
+        let words = bytes.div_ceil(std::mem::size_of::<u64>());
+        self.buff = vec![0u64; words];
+    }
+
+    /// The request method, e.g. `GET`. Verbs HTTP.SYS doesn't know, such as `PATCH`, are read
+    /// from the unknown verb string.
+    pub fn method(&self) -> String {
+        let raw = self.header();
+        if let Some((_, name)) = KNOWN_VERBS.iter().find(|(verb, _)| *verb == raw.Base.Verb) {
+            return name.to_string();
+        }
+
+        raw_string(raw.Base.pUnknownVerb, raw.Base.UnknownVerbLength).unwrap_or_default()
+    }
+
+    /// The request headers, keyed by name as the client sent it for unknown headers and by
+    /// canonical name, e.g. `Content-Type`, for those HTTP.SYS parses itself. Headers with no
+    /// value are left out.
+    pub fn headers(&self) -> HashMap<String, String> {
+        let headers = &self.header().Base.Headers;
+        let mut result = HashMap::new();
+
+        for (name, known) in KNOWN_HEADERS.iter().zip(headers.KnownHeaders.iter()) {
+            if let Some(value) = raw_string(known.pRawValue, known.RawValueLength) {
+                result.insert(name.to_string(), value);
+            }
+        }
+
+        if !headers.pUnknownHeaders.is_null() {
+            let unknown: &[HTTP_UNKNOWN_HEADER] = unsafe {
+                std::slice::from_raw_parts(
+                    headers.pUnknownHeaders,
+                    headers.UnknownHeaderCount as usize,
+                )
+            };
+            for header in unknown {
+                if let (Some(name), Some(value)) = (
+                    raw_string(header.pName, header.NameLength),
+                    raw_string(header.pRawValue, header.RawValueLength),
+                ) {
+                    result.insert(name, value);
+                }
+            }
+        }
+
+        result
     }
 
     pub fn url(&self) -> String {
-        if self.raw.Base.pRawUrl != PCSTR::null() {
-            let c_ptr = self.raw.Base.pRawUrl.0 as *const c_char;
+        let raw = self.header();
+        if raw.Base.pRawUrl != PCSTR::null() {
+            let c_ptr = raw.Base.pRawUrl.0 as *const c_char;
             unsafe {
                 let cs = CStr::from_ptr(c_ptr);
                 cs.to_string_lossy().into_owned()
@@ -382,12 +515,87 @@ impl Request {
 unsafe impl Send for Request {}
 unsafe impl Sync for Request {}
 
+/// Reads a length-prefixed string out of the request buffer, or `None` if it's null or empty.
+fn raw_string(ptr: PCSTR, len: u16) -> Option<String> {
+    if ptr.is_null() || len == 0 {
+        return None;
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(ptr.0, len as usize) };
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Names of the request headers HTTP.SYS parses itself, indexed by `HTTP_HEADER_ID`.
+const KNOWN_HEADERS: [&str; 41] = [
+    "Cache-Control",
+    "Connection",
+    "Date",
+    "Keep-Alive",
+    "Pragma",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Via",
+    "Warning",
+    "Allow",
+    "Content-Length",
+    "Content-Type",
+    "Content-Encoding",
+    "Content-Language",
+    "Content-Location",
+    "Content-MD5",
+    "Content-Range",
+    "Expires",
+    "Last-Modified",
+    "Accept",
+    "Accept-Charset",
+    "Accept-Encoding",
+    "Accept-Language",
+    "Authorization",
+    "Cookie",
+    "Expect",
+    "From",
+    "Host",
+    "If-Match",
+    "If-Modified-Since",
+    "If-None-Match",
+    "If-Range",
+    "If-Unmodified-Since",
+    "Max-Forwards",
+    "Proxy-Authorization",
+    "Referer",
+    "Range",
+    "TE",
+    "Translate",
+    "User-Agent",
+];
+
+/// Names of the verbs HTTP.SYS parses itself.
+const KNOWN_VERBS: [(HTTP_VERB, &str); 17] = [
+    (HttpVerbOPTIONS, "OPTIONS"),
+    (HttpVerbGET, "GET"),
+    (HttpVerbHEAD, "HEAD"),
+    (HttpVerbPOST, "POST"),
+    (HttpVerbPUT, "PUT"),
+    (HttpVerbDELETE, "DELETE"),
+    (HttpVerbTRACE, "TRACE"),
+    (HttpVerbCONNECT, "CONNECT"),
+    (HttpVerbTRACK, "TRACK"),
+    (HttpVerbMOVE, "MOVE"),
+    (HttpVerbCOPY, "COPY"),
+    (HttpVerbPROPFIND, "PROPFIND"),
+    (HttpVerbPROPPATCH, "PROPPATCH"),
+    (HttpVerbMKCOL, "MKCOL"),
+    (HttpVerbLOCK, "LOCK"),
+    (HttpVerbUNLOCK, "UNLOCK"),
+    (HttpVerbSEARCH, "SEARCH"),
+];
+
 #[derive(Default)]
 #[repr(C)]
 pub struct Response {
     pub(crate) raw: HTTP_RESPONSE_V2,
     data_chunks: Box<HTTP_DATA_CHUNK>,
-    strings: String,
+    body: Vec<u8>,
 }
 unsafe impl Send for Response {}
 unsafe impl Sync for Response {}
@@ -397,13 +605,13 @@ impl Response {
         &self.raw
     }
 
-    pub fn add_body_chunk(&mut self, data: &str) {
-        self.strings = data.to_string();
+    pub fn add_body_chunk(&mut self, data: &[u8]) {
+        self.body = data.to_vec();
 
         let mut chunk = Box::<HTTP_DATA_CHUNK>::default();
         chunk.DataChunkType = HttpDataChunkFromMemory;
-        chunk.Anonymous.FromMemory.BufferLength = self.strings.len() as u32;
-        chunk.Anonymous.FromMemory.pBuffer = self.strings.as_mut_ptr() as *mut std::ffi::c_void;
+        chunk.Anonymous.FromMemory.BufferLength = self.body.len() as u32;
+        chunk.Anonymous.FromMemory.pBuffer = self.body.as_mut_ptr() as *mut std::ffi::c_void;
 
         self.raw.Base.EntityChunkCount = 1;
         self.raw.Base.pEntityChunks = &mut *chunk;
@@ -443,54 +651,152 @@ impl RequestQueue {
         url_group.set_binding_info(&info)
     }
 
+    /// Receives the next request into `requestbuffer`. If the request doesn't fit, HTTP.SYS
+    /// keeps it queued and reports its id, so the buffer is grown and the same request is
+    /// received again.
     pub async fn async_receive_request(
         &self,
         requestid: u64,
         flags: HTTP_RECEIVE_HTTP_REQUEST_FLAGS,
         requestbuffer: &mut Request,
+    ) -> Result<u32, Error> {
+        let mut requestid = requestid;
+
+        loop {
+            let optr = Arc::new(OverlappedObject::new());
+            let ec = unsafe {
+                HttpReceiveHttpRequest(
+                    self.h,
+                    requestid,
+                    flags,
+                    requestbuffer.raw(),
+                    requestbuffer.size(),
+                    None,
+                    Some(optr.get()),
+                )
+            };
+            let err = WIN32_ERROR(ec);
+            let required = if err == ERROR_IO_PENDING || err == NO_ERROR {
+                match OverlappedFuture::started(self.h, &optr).await {
+                    Ok(len) => return Ok(len),
+                    Err(e) if e != Error::from(ERROR_INSUFFICIENT_BUFFER) => return Err(e),
+                    Err(_) => optr.get_len() as usize,
+                }
+            } else if err == ERROR_INSUFFICIENT_BUFFER {
+                // No size hint on the synchronous path, grow() doubles the buffer instead
+                0
+            } else {
+                return Err(Error::from(err));
+            };
+
+            requestid = requestbuffer.raw().Base.RequestId;
+            requestbuffer.grow(required);
+        }
+    }
+
+    /// Reads the entity body of a request returned by `async_receive_request`. HTTP.SYS
+    /// doesn't copy the body into the request buffer, so it is read here chunk by chunk.
+    pub async fn async_receive_entity_body(&self, request: &Request) -> Result<Vec<u8>, Error> {
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let mut body = Vec::new();
+        let raw = request.header();
+        if raw.Base.Flags & HTTP_REQUEST_FLAG_MORE_ENTITY_BODY_EXISTS == 0 {
+            return Ok(body);
+        }
+
+        let requestid = raw.Base.RequestId;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+
+        loop {
+            let optr = Arc::new(OverlappedObject::new());
+            let ec = unsafe {
+                HttpReceiveRequestEntityBody(
+                    self.h,
+                    requestid,
+                    0,
+                    chunk.as_mut_ptr() as *mut std::ffi::c_void,
+                    chunk.len() as u32,
+                    None,
+                    Some(optr.get()),
+                )
+            };
+            let err = WIN32_ERROR(ec);
+            if err == ERROR_HANDLE_EOF {
+                break;
+            }
+            if err != ERROR_IO_PENDING && err != NO_ERROR {
+                return Err(Error::from(err));
+            }
+
+            let len = match OverlappedFuture::started(self.h, &optr).await {
+                Ok(len) => len as usize,
+                Err(e) if e == Error::from(ERROR_HANDLE_EOF) => break,
+                Err(e) => return Err(e),
+            };
+            if len == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..len]);
+        }
+
+        Ok(body)
+    }
+
+    pub async fn async_send_response(
+        &self,
+        requestid: u64,
+        flags: u32,
+        httpresponse: &Response,
     ) -> Result<u32, Error> {
         let optr = Arc::new(OverlappedObject::new());
         let ec = unsafe {
-            HttpReceiveHttpRequest(
+            HttpSendHttpResponse(
                 self.h,
                 requestid,
                 flags,
-                requestbuffer.raw(),
-                Request::size(),
+                httpresponse.raw(),
                 None,
+                None,
+                None,
+                0,
                 Some(optr.get()),
+                None,
             )
         };
         let err = WIN32_ERROR(ec);
+
         if err == ERROR_IO_PENDING || err == NO_ERROR {
-            std::mem::forget(optr.clone());
-            optr.wait().await;
-            let async_err = optr.get_ec();
-            if async_err == Error::OK {
-                Ok(optr.get_len())
-            } else {
-                Err(async_err)
-            }
+            OverlappedFuture::started(self.h, &optr).await
         } else {
-            assert_ne!(err, ERROR_INSUFFICIENT_BUFFER);
             Err(Error::from(err))
         }
     }
 
-    pub async fn async_send_response(
+    /// Sends one more piece of the body of a response started with `async_send_response` and
+    /// the more-data flag. `flags` carries the more-data flag on every piece but the last.
+    pub async fn async_send_entity_body(
         &self,
         requestid: u64,
         flags: u32,
-        httpresponse: &Response,
+        data: &[u8],
     ) -> Result<u32, Error> {
+        let mut chunk = HTTP_DATA_CHUNK {
+            DataChunkType: HttpDataChunkFromMemory,
+            ..Default::default()
+        };
+        chunk.Anonymous.FromMemory.BufferLength = data.len() as u32;
+        chunk.Anonymous.FromMemory.pBuffer = data.as_ptr() as *mut std::ffi::c_void;
+
+        // Read until the send completes, so it must outlive the call
+        let chunks = [chunk];
         let optr = Arc::new(OverlappedObject::new());
         let ec = unsafe {
-            HttpSendHttpResponse(
+            HttpSendResponseEntityBody(
                 self.h,
                 requestid,
                 flags,
-                httpresponse.raw(),
-                None,
+                Some(&chunks),
                 None,
                 None,
                 0,
@@ -501,15 +807,7 @@ impl RequestQueue {
         let err = WIN32_ERROR(ec);
 
         if err == ERROR_IO_PENDING || err == NO_ERROR {
-            std::mem::forget(optr.clone());
-            let _ = OverlappedFuture::new(self.h, unsafe { *optr.get() }).await;
-            optr.wait().await;
-            let async_err = optr.get_ec();
-            if async_err == Error::OK {
-                Ok(optr.get_len())
-            } else {
-                Err(async_err)
-            }
+            OverlappedFuture::started(self.h, &optr).await
         } else {
             Err(Error::from(err))
         }
@@ -530,4 +828,4 @@ impl Drop for RequestQueue {
     fn drop(&mut self) {
         self.close()
     }
-}
\ No newline at end of file
+}
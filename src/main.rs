@@ -1,23 +1,59 @@
-use clap::{command, Parser, Subcommand};
-use plotters::prelude::*;
-use plotters::style::{BLUE, WHITE};
+use aggregate::aggregate;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use client::{
+    build_async_client, build_client, build_raw_client, check_echo, check_status, gzip_body,
+    measure_phases, send_compressed_request, send_request, send_request_async, set_cookies,
+    with_proxy_credentials, ClientOptions, HttpResponse, PhaseTimings, TlsVersion,
+};
+use compare::{compare_runs, print_comparison};
+use grpc::GrpcClient;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn, LevelFilter};
+use pipeline::{Pipeliner, Target};
+use plot::{
+    load_measurements, save_measurements, write_comparison_plot, write_plot, write_timeseries_plot,
+    Measurement, PlotKind, PlotMetric, PLOT_HEIGHT, PLOT_WIDTH, TREND_WINDOW,
+};
+use prometheus::{write_prometheus, PromSeries};
 use rand::distributions::Alphanumeric;
 use rand::prelude::Distribution;
-use rand::thread_rng;
-use reqwest::blocking::Client;
-use reqwest::{Proxy, Url};
-use server::Server;
-use std::collections::BTreeMap;
-use std::error::Error;
-use std::time::Instant;
-use std::{thread, time::Duration};
-use tokio::signal;
-use tokio::task;
-use util::print_latency;
-use util::{measure_latency, run_this_exe_as_server};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use reqwest::{Method, Url};
+use serde::Serialize;
+use server::{Handler, HandlerFn, HandlerResponse, Server, StreamedBody};
+use std::cell::Cell;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+use udp::UdpPinger;
+use util::{
+    combine_runs, measure_latency_at_rate, print_histogram, print_latency, print_queueing,
+    print_runs, print_side_by_side, print_warmup, FirstFailure, LatencyMeasurement, Retried,
+    LISTENING_PREFIX, MIN_RATE, READY_LINE,
+};
+use util::{
+    format_size, format_throughput, measure_latency_async, measure_latency_concurrent,
+    measure_latency_count, measure_latency_for, parse_duration, parse_size, run_this_exe_as_server,
+    run_this_exe_as_udp_server, throughput_mb_per_sec, with_retries, with_retries_async, write_csv,
+    MeasureConfig,
+};
 
+mod aggregate;
+mod client;
+mod compare;
+mod grpc;
+#[cfg(windows)]
 mod httpsys;
+mod pipeline;
+mod plot;
+mod prometheus;
 mod server;
+mod udp;
 mod util;
 
 /// Network latency tester.
@@ -27,7 +63,7 @@ struct Args {
     #[command(subcommand)]
     command: Mode,
 
-    /// Validate SSL certificates. If set to false, invalid certificates will be accepted.
+    /// Skip SSL certificate validation. When set, invalid certificates will be accepted.
     /// This is useful for development or testing with self-signed certificates but is
     /// not recommended for production environments due to security risks.
     #[arg(
@@ -37,8 +73,228 @@ struct Args {
         help = "Don't Validate SSL certificates"
     )]
     no_validate_certs: bool,
+
+    /// Output format for the Client, Echo and Connect modes.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "Output format"
+    )]
+    format: OutputFormat,
+
+    /// How much is logged on stderr, on top of the results. Warnings and errors are always
+    /// logged. `RUST_LOG` replaces it when set, e.g. `RUST_LOG=debug,h2=trace`.
+    #[arg(
+        short,
+        long,
+        action = clap::ArgAction::Count,
+        help = "Log more on stderr: -v for info, -vv for debug, -vvv for trace"
+    )]
+    verbose: u8,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human readable lines.
+    Text,
+    /// A single JSON object.
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[value(rename_all = "UPPER")]
+enum HttpMethod {
+    Get,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Head,
+}
+
+impl From<HttpMethod> for Method {
+    fn from(method: HttpMethod) -> Self {
+        match method {
+            HttpMethod::Get => Method::GET,
+            HttpMethod::Post => Method::POST,
+            HttpMethod::Put => Method::PUT,
+            HttpMethod::Patch => Method::PATCH,
+            HttpMethod::Delete => Method::DELETE,
+            HttpMethod::Head => Method::HEAD,
+        }
+    }
+}
+
+/// What the generated payloads are made of. Compressible and incompressible bodies behave very
+/// differently through proxies and TLS, so the choice changes what is measured.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum PayloadPattern {
+    /// Random letters and digits, which barely compress.
+    #[default]
+    Random,
+    /// Zero bytes, which compress almost entirely.
+    Zeros,
+    /// Random words of lorem ipsum, compressible like prose.
+    Text,
+    /// Random bytes over the full range, which don't compress at all.
+    Binary,
+}
+
+/// How the server's `--jitter` spreads each response's delay around `--delay`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum JitterDistribution {
+    /// Anywhere within the jitter either side, each equally likely.
+    #[default]
+    Uniform,
+    /// Normally distributed with the jitter as the standard deviation, so most delays are near
+    /// the mean and a few are far from it.
+    Normal,
+}
+
+#[derive(Serialize)]
+struct ClientReport<'a> {
+    url: &'a str,
+    mean_latency_ns: u128,
+    min_latency_ns: u128,
+    max_latency_ns: u128,
+    p50_latency_ns: u128,
+    p90_latency_ns: u128,
+    p99_latency_ns: u128,
+    std_dev_ns: u128,
+    ci_low_ns: u128,
+    ci_high_ns: u128,
+    cv: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmup_latency_ns: Option<Vec<u128>>,
+    /// Each run's mean latency, when the measurement was repeated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_mean_latency_ns: Option<Vec<u128>>,
+    iterations: usize,
+    concurrency: u32,
+    requests_per_second: f64,
+    /// Fraction of the measured requests that failed, which are left out of the latencies.
+    failure_rate: f64,
+    /// Requests per second asked for with `--rate`, along with how long requests waited past
+    /// their due time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_delay_mean_ns: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_delay_p99_ns: Option<u128>,
+    /// Whether HTTP connections were reused, so the two reports of `--compare-keepalive` can
+    /// be told apart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keepalive: Option<bool>,
+    /// Whether requests went through the proxy, so the two reports of `--compare-proxy` can be
+    /// told apart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxied: Option<bool>,
+    /// Requests written per batch in pipeline mode, where each latency covers a whole batch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pipeline_depth: Option<usize>,
+    successes: usize,
+    failures: usize,
+    retries: usize,
+}
+
+impl<'a> ClientReport<'a> {
+    /// Reports one measurement made over a single connection. Modes override the fields they
+    /// know more about, such as the concurrency or the counts they kept themselves.
+    fn from_latency(url: &'a str, latency: &LatencyMeasurement, measure: &MeasureConfig) -> Self {
+        ClientReport {
+            url,
+            mean_latency_ns: latency.latency.as_nanos(),
+            min_latency_ns: latency.min.as_nanos(),
+            max_latency_ns: latency.max.as_nanos(),
+            p50_latency_ns: latency.p50.as_nanos(),
+            p90_latency_ns: latency.p90.as_nanos(),
+            p99_latency_ns: latency.p99.as_nanos(),
+            std_dev_ns: latency.std_dev.as_nanos(),
+            ci_low_ns: latency.ci_low.as_nanos(),
+            ci_high_ns: latency.ci_high.as_nanos(),
+            cv: latency.cv,
+            warmup_latency_ns: measure
+                .report_warmup
+                .then(|| latency.warmup.iter().map(|d| d.as_nanos()).collect()),
+            run_mean_latency_ns: None,
+            iterations: latency.iterations,
+            concurrency: 1,
+            requests_per_second: latency.requests_per_second,
+            failure_rate: latency.failure_rate(),
+            target_rate: None,
+            queue_delay_mean_ns: None,
+            queue_delay_p99_ns: None,
+            keepalive: None,
+            proxied: None,
+            pipeline_depth: None,
+            successes: latency.iterations - latency.failures,
+            failures: latency.failures,
+            retries: 0,
+        }
+    }
+}
+
+/// One line of `--stream` output, written as each request completes.
+#[derive(Serialize)]
+struct RequestEvent<'a> {
+    /// The series the request belongs to, so that `aggregate` keeps e.g. the two sides of
+    /// `--compare-proxy` apart.
+    name: &'a str,
+    payload_size: u64,
+    /// When the request completed, in milliseconds since the Unix epoch.
+    ts: u128,
+    latency_ns: u128,
+    /// `None` when no response was received.
+    status: Option<u16>,
+    /// Whether the request was counted as a success, which also depends on `--expect-status`.
+    ok: bool,
+    /// Whether the request was a warmup, left out of the summary.
+    warmup: bool,
+}
+
+#[derive(Serialize)]
+struct EchoReport<'a> {
+    url: &'a str,
+    latency_ns: u128,
+    response_size: usize,
+    wire_size: usize,
+    content_encoding: Option<String>,
+    status: Option<u16>,
+    http_version: Option<String>,
+    final_url: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    set_cookies: Vec<String>,
+    error: Option<String>,
+    phases: Option<PhaseReport>,
+}
+
+#[derive(Serialize)]
+struct PhaseReport {
+    dns_ns: u128,
+    connect_ns: u128,
+    tls_ns: Option<u128>,
+    tls_version: Option<&'static str>,
+    first_byte_ns: u128,
+    total_ns: u128,
+}
+
+impl From<&PhaseTimings> for PhaseReport {
+    fn from(phases: &PhaseTimings) -> Self {
+        PhaseReport {
+            dns_ns: phases.dns.as_nanos(),
+            connect_ns: phases.connect.as_nanos(),
+            tls_ns: phases.tls.map(|tls| tls.as_nanos()),
+            tls_version: phases.tls_version.map(TlsVersion::name),
+            first_byte_ns: phases.first_byte.as_nanos(),
+            total_ns: phases.total.as_nanos(),
+        }
+    }
 }
 
+// Parsed once at startup, so the client's many options needn't be boxed
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug, Clone)]
 enum Mode {
     /// Starts the HTTP server.
@@ -46,6 +302,35 @@ enum Mode {
     Server {
         #[arg(help = "The URL to receive requests on", default_value = "http://localhost:8080", value_parser = is_valid_url)]
         receive_url: Url,
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), help = "Number of requests received and handled in parallel")]
+        workers: u32,
+        #[arg(
+            long,
+            help = "Answer /test with the contents of this file instead of OK"
+        )]
+        serve_file: Option<PathBuf>,
+        #[arg(long, conflicts_with = "serve_file", value_parser = parse_size, help = "Answer /test with a generated body of this size, streamed in 64kb chunks (example 8mb)")]
+        response_size: Option<usize>,
+        #[arg(long, value_parser = parse_duration, help = "Wait this long before answering each /test request (example 50ms)")]
+        delay: Option<Duration>,
+        #[arg(long, value_parser = parse_duration, help = "Vary each /test request's delay around --delay by a random amount on this scale (example 5ms)")]
+        jitter: Option<Duration>,
+        #[arg(long, value_enum, requires = "jitter", default_value_t = JitterDistribution::Uniform, help = "How the jittered delays are spread around --delay")]
+        jitter_dist: JitterDistribution,
+        #[arg(
+            long = "prefix",
+            value_name = "PATH",
+            help = "Extra path answered like /test, may be repeated (example /api)"
+        )]
+        prefixes: Vec<String>,
+        #[arg(long, default_value = "5s", value_parser = parse_duration, help = "On shutdown, wait this long for requests already being answered before giving up on them")]
+        drain_timeout: Duration,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Also answer requests on a Unix domain socket at this path (example /tmp/netbench.sock)"
+        )]
+        uds: Option<PathBuf>,
     },
     /// Sends requests to the server and measures latency.
     #[command(alias = "c")]
@@ -54,6 +339,107 @@ enum Mode {
         send_url: Url,
         #[arg(help = "Optional proxy server URL (example http://localhost:8080)")]
         proxy_url: Option<Url>,
+        #[arg(
+            long,
+            requires = "proxy_url",
+            help = "User name for proxy authentication, overriding any in the proxy URL"
+        )]
+        proxy_user: Option<String>,
+        #[arg(
+            long,
+            requires = "proxy_user",
+            help = "Password for proxy authentication"
+        )]
+        proxy_pass: Option<String>,
+        #[arg(short, long, value_enum, ignore_case = true, default_value_t = HttpMethod::Get, help = "HTTP method to send")]
+        method: HttpMethod,
+        #[arg(long, conflicts_with = "body_file", help = "Request body to send")]
+        body: Option<String>,
+        #[arg(long, help = "File whose contents are sent as the request body")]
+        body_file: Option<PathBuf>,
+        #[arg(long, conflicts_with_all = ["body", "body_file"], value_parser = parse_size, help = "Send a generated body of this size (example 64kb)")]
+        payload_size: Option<usize>,
+        #[arg(long, value_enum, requires = "payload_size", default_value_t = PayloadPattern::Random, help = "What the generated body is made of")]
+        payload_pattern: PayloadPattern,
+        #[arg(
+            long,
+            help = "Gzip the body before sending it, with a Content-Encoding: gzip header"
+        )]
+        compress_body: bool,
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), help = "Number of concurrent connections sending requests")]
+        concurrency: u32,
+        #[arg(
+            long,
+            conflicts_with = "no_keepalive",
+            help = "Send one request per connection at once before measuring, so every pooled connection is open"
+        )]
+        warm_connections: bool,
+        #[arg(long, conflicts_with_all = ["no_keepalive", "csv"], help = "Measure with connections reused, then again with a new connection for every request")]
+        compare_keepalive: bool,
+        #[arg(long, requires = "proxy_url", conflicts_with_all = ["compare_keepalive", "csv"], help = "Measure straight to the URL, then again through the proxy")]
+        compare_proxy: bool,
+        #[arg(
+            long,
+            value_name = "RPS",
+            conflicts_with = "warm_connections",
+            help = "Send this many requests per second on a fixed schedule instead of as fast as responses come back"
+        )]
+        rate: Option<f64>,
+        #[arg(short, long, value_parser = parse_duration, help = "Send requests for this long instead of until the latency is stable (example 500ms, 30s, 2m)")]
+        duration: Option<Duration>,
+        #[arg(short = 'n', long, conflicts_with_all = ["duration", "rate"], value_parser = clap::value_parser!(u64).range(1..), help = "Measure exactly this many requests after the warmups instead of until the latency is stable, keeping every sample")]
+        count: Option<u64>,
+        #[arg(long, help = "Write each measured latency to this CSV file")]
+        csv: Option<PathBuf>,
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), help = "Run the whole measurement this many times to see how much runs vary")]
+        repeat: u32,
+        #[arg(long, help = "Print a JSON line for every request as it completes")]
+        stream: bool,
+        #[arg(
+            short,
+            long,
+            help = "Don't print the summary, e.g. when only the streamed lines or the exit code are wanted"
+        )]
+        quiet: bool,
+        #[arg(
+            long,
+            help = "Save the average latency as JSON so it can be plotted with the plot command"
+        )]
+        save: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Draw the average latency as a bar plot, a bar per measurement, .svg or .png"
+        )]
+        plot: Option<String>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Draw every measured request's latency in the order they were sent, with a moving average, .svg or .png"
+        )]
+        trend_plot: Option<String>,
+        #[arg(long, value_name = "N", requires = "trend_plot", default_value_t = TREND_WINDOW as u32, value_parser = clap::value_parser!(u32).range(1..), help = "Requests the trend plot's moving average is taken over")]
+        trend_window: u32,
+        #[arg(
+            long,
+            requires = "trend_plot",
+            help = "Draw dashed lines at each measurement's p50, p95 and p99 latency across the trend plot"
+        )]
+        trend_percentiles: bool,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Write the run's metrics to this file in Prometheus text format, e.g. for node_exporter's textfile collector"
+        )]
+        prom_out: Option<PathBuf>,
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Exit with code 1 when the p99 latency is over this, e.g. as a CI gate (example 50ms)")]
+        slo_p99: Option<Duration>,
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Exit with code 1 when the mean latency is over this")]
+        slo_mean: Option<Duration>,
+        #[command(flatten)]
+        options: ClientOptions,
+        #[command(flatten)]
+        measure: MeasureConfig,
     },
     /// Sends requests to the server and prints the result.
     #[command(alias = "e")]
@@ -62,23 +448,533 @@ enum Mode {
         send_url: Url,
         #[arg(help = "Optional proxy server URL (example http://localhost:8080)")]
         proxy_url: Option<Url>,
+        #[arg(
+            long,
+            requires = "proxy_url",
+            help = "User name for proxy authentication, overriding any in the proxy URL"
+        )]
+        proxy_user: Option<String>,
+        #[arg(
+            long,
+            requires = "proxy_user",
+            help = "Password for proxy authentication"
+        )]
+        proxy_pass: Option<String>,
+        #[command(flatten)]
+        options: ClientOptions,
+    },
+    /// Opens TCP connections to a host and measures how long each connect takes, without
+    /// sending any HTTP.
+    Connect {
+        #[arg(help = "The host and port to connect to (example localhost:8080)")]
+        target: String,
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), help = "Number of connections opened at once")]
+        concurrency: u32,
+        #[arg(long, value_parser = parse_duration, help = "Give up on a connect that takes longer than this (example 500ms, 5s)")]
+        timeout: Option<Duration>,
+        #[command(flatten)]
+        measure: MeasureConfig,
+    },
+    /// Writes several GET requests onto one connection before reading any of the responses,
+    /// as HTTP/1.1 pipelining, and measures how long each batch takes.
+    Pipeline {
+        #[arg(help = "The http URL to send requests to", default_value = "http://localhost:8080/test/", value_parser = is_valid_url)]
+        send_url: Url,
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..), help = "Requests written before their responses are read")]
+        depth: u32,
+        #[arg(long, default_value = "5s", value_parser = parse_duration, help = "Count a batch as failed when its responses take longer than this")]
+        timeout: Duration,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Connect to the server's Unix domain socket at this path instead of the URL's host and port"
+        )]
+        uds: Option<PathBuf>,
+        #[command(flatten)]
+        measure: MeasureConfig,
+    },
+    /// Makes unary gRPC calls over HTTP/2 and measures how long each call takes.
+    Grpc {
+        #[arg(help = "The URL of the gRPC server, http for HTTP/2 without TLS (example http://localhost:50051)", value_parser = is_valid_url)]
+        send_url: Url,
+        #[arg(help = "The method to call (example helloworld.Greeter/SayHello)")]
+        method: String,
+        #[arg(
+            long,
+            help = "File holding the protobuf encoded request message, empty when not given"
+        )]
+        body_file: Option<PathBuf>,
+        #[arg(long, default_value = "5s", value_parser = parse_duration, help = "Count a call as failed when its response takes longer than this")]
+        timeout: Duration,
+        #[command(flatten)]
+        measure: MeasureConfig,
+    },
+    /// Measures UDP round trips against an echo server, as a comparison point without HTTP.
+    Udp {
+        #[command(subcommand)]
+        role: UdpRole,
     },
     /// Starts this app as a server and measures latency.
     #[command(alias = "t")]
-    Test,
+    Test {
+        #[arg(long, default_value = "1kb", value_parser = parse_size, help = "Smallest payload size to send (example 64b, 64kb, 1mb)")]
+        min_size: usize,
+        #[arg(long, default_value = "8mb", value_parser = parse_size, help = "Largest payload size to send")]
+        max_size: usize,
+        #[arg(
+            long,
+            default_value_t = 1.25,
+            help = "Multiplier applied to the payload size after each step"
+        )]
+        growth: f64,
+        #[arg(
+            short,
+            long,
+            default_value = "request-latency.svg",
+            help = "Plot file to write, .svg or .png"
+        )]
+        output: String,
+        #[arg(
+            long,
+            help = "Write the plot, CSV and JSON into this directory under timestamped names, keeping earlier runs"
+        )]
+        output_dir: Option<PathBuf>,
+        #[arg(long, default_value_t = PLOT_WIDTH, value_parser = clap::value_parser!(u32).range(200..=8000), help = "Plot width in pixels")]
+        plot_width: u32,
+        #[arg(long, default_value_t = PLOT_HEIGHT, value_parser = clap::value_parser!(u32).range(200..=8000), help = "Plot height in pixels")]
+        plot_height: u32,
+        #[arg(long, default_value = PLOT_CAPTION, help = "Plot caption")]
+        plot_title: String,
+        #[arg(long, help = "Write each measured latency to this CSV file")]
+        csv: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value = "Request",
+            help = "Name of this run's series in the plot legend"
+        )]
+        name: String,
+        #[arg(
+            long,
+            help = "Save the measurements as JSON so they can be re-plotted with the plot command"
+        )]
+        save: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Write the run's metrics to this file in Prometheus text format, with a series per payload size"
+        )]
+        prom_out: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = PlotMetric::Latency, help = "Value plotted on the Y axis")]
+        metric: PlotMetric,
+        #[arg(
+            long,
+            help = "Seed for the random payloads so every run sends the same bytes"
+        )]
+        seed: Option<u64>,
+        #[arg(long, value_enum, default_value_t = PayloadPattern::Random, conflicts_with = "body_file", help = "What the generated payloads are made of")]
+        payload_pattern: PayloadPattern,
+        #[arg(
+            long,
+            conflicts_with = "seed",
+            help = "Send this file's contents as the payload instead of random data, in a single step"
+        )]
+        body_file: Option<PathBuf>,
+        #[arg(
+            short,
+            long,
+            help = "Don't show a progress bar while each payload size is measured"
+        )]
+        quiet: bool,
+        #[arg(
+            long,
+            default_value = "/test/",
+            help = "Path on the started server to POST each payload to (example /echo)"
+        )]
+        path: String,
+        #[arg(
+            long,
+            help = "Count a response whose body isn't the payload sent as a failure, e.g. with --path /echo"
+        )]
+        verify_echo: bool,
+        #[arg(
+            long,
+            conflicts_with = "verify_echo",
+            help = "Gzip each payload before sending it, with a Content-Encoding: gzip header"
+        )]
+        compress_body: bool,
+        #[arg(
+            long,
+            help = "Print the payload sizes and calls the run would make, without starting the server"
+        )]
+        dry_run: bool,
+        #[command(flatten)]
+        options: ClientOptions,
+        #[command(flatten)]
+        measure: MeasureConfig,
+    },
+    /// Plots measurements saved by the test command, overlaying several files if given.
+    #[command(alias = "p")]
+    Plot {
+        #[arg(required = true, help = "Saved measurement files to plot")]
+        inputs: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            default_value = "request-latency.svg",
+            help = "Plot file to write, .svg or .png"
+        )]
+        output: String,
+        #[arg(long, default_value = PLOT_CAPTION, help = "Plot caption")]
+        caption: String,
+        #[arg(long, value_enum, default_value_t = PlotMetric::Latency, help = "Value plotted on the Y axis")]
+        metric: PlotMetric,
+        #[arg(long, value_enum, default_value_t = PlotKind::Line, help = "Draw a line per series across sizes, or one bar per series")]
+        kind: PlotKind,
+    },
+    /// Combines latency samples given as JSON lines, e.g. `client --stream` output collected
+    /// from several machines, and prints and plots their statistics.
+    Aggregate {
+        #[arg(
+            default_value = "-",
+            help = "File of JSON lines with a latency_ns and optionally a payload_size and name, or - for stdin"
+        )]
+        input: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Also plot the combined samples to this .svg or .png file"
+        )]
+        output: Option<String>,
+        #[arg(long, default_value = "Combined samples", help = "Plot caption")]
+        caption: String,
+        #[arg(long, value_enum, default_value_t = PlotMetric::Latency, help = "Value plotted on the Y axis")]
+        metric: PlotMetric,
+        #[arg(long, value_enum, default_value_t = PlotKind::Line, help = "Draw a line per name across sizes, or one bar per name")]
+        kind: PlotKind,
+        #[arg(
+            long,
+            help = "Save the combined statistics as JSON for the plot and compare commands"
+        )]
+        save: Option<PathBuf>,
+    },
+    /// Compares two runs saved by `test --save` and fails if any point got slower than the
+    /// threshold allows.
+    Compare {
+        #[arg(help = "Saved measurements to compare against")]
+        baseline: PathBuf,
+        #[arg(help = "Saved measurements of the run being checked")]
+        current: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 10.0,
+            help = "Percent a point's latency may rise before it counts as a regression"
+        )]
+        threshold: f64,
+        #[arg(
+            short,
+            long,
+            help = "Also plot both runs, with regressed points in red, to this .svg or .png file"
+        )]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum UdpRole {
+    /// Echoes every datagram back to its sender.
+    #[command(alias = "s")]
+    Server {
+        #[arg(
+            help = "The address to receive datagrams on",
+            default_value = "127.0.0.1:9000"
+        )]
+        bind: String,
+    },
+    /// Sends datagrams to an echo server and measures the round trip.
+    #[command(alias = "c")]
+    Client {
+        #[arg(help = "The host and port of the echo server (example localhost:9000)")]
+        target: String,
+        #[command(flatten)]
+        ping: UdpPingOptions,
+    },
+    /// Starts this app as a UDP echo server and measures the round trip to it.
+    #[command(alias = "t")]
+    Test {
+        #[command(flatten)]
+        ping: UdpPingOptions,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct UdpPingOptions {
+    #[arg(long, default_value = "64b", value_parser = parse_size, help = "Datagram size, at most 65507 bytes (example 64b, 1kb)")]
+    size: usize,
+    #[arg(long, default_value = "1s", value_parser = parse_duration, help = "Count a datagram as lost when its echo takes longer than this")]
+    timeout: Duration,
+    #[command(flatten)]
+    measure: MeasureConfig,
+}
+
+const PLOT_CAPTION: &str = "Same Machine HTTP requests to HTTP-SYS";
+
+fn validate_measure_config(measure: &MeasureConfig) {
+    if measure.max_iterations == 0 {
+        Args::command()
+            .error(ErrorKind::ValueValidation, "--max-iters must be at least 1")
+            .exit();
+    }
+    if measure.max_iterations < measure.min_iterations {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--max-iters must be >= --min-iters",
+            )
+            .exit();
+    }
+    if measure.stable_threshold <= 0.0 {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--stable-threshold must be greater than 0",
+            )
+            .exit();
+    }
+    if measure.outlier_threshold <= 0.0 {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--outlier-sigma must be greater than 0",
+            )
+            .exit();
+    }
+}
+
+/// Paths for a run's plot, CSV and JSON in `dir`, named after the `output` plot file with the
+/// time appended, e.g. `request-latency-20240101-120000.svg`, so each run keeps its own files.
+fn timestamped_outputs(
+    dir: &Path,
+    output: &str,
+    time: chrono::NaiveDateTime,
+) -> (PathBuf, PathBuf, PathBuf) {
+    let output = Path::new(output);
+    let stem = output
+        .file_stem()
+        .map_or("request-latency".into(), |stem| stem.to_string_lossy());
+    let extension = output
+        .extension()
+        .map_or("svg".into(), |extension| extension.to_string_lossy());
+    let name = format!("{stem}-{}", time.format("%Y%m%d-%H%M%S"));
+    (
+        dir.join(format!("{name}.{extension}")),
+        dir.join(format!("{name}.csv")),
+        dir.join(format!("{name}.json")),
+    )
+}
+
+fn validate_client_options(options: &ClientOptions) {
+    if let Err(message) = options.check_tls_versions() {
+        Args::command()
+            .error(ErrorKind::ValueValidation, message)
+            .exit();
+    }
+}
+
+fn proxied_url(
+    proxy_url: &Option<Url>,
+    proxy_user: &Option<String>,
+    proxy_pass: &Option<String>,
+) -> Option<Url> {
+    with_proxy_credentials(proxy_url, proxy_user, proxy_pass)
+        .unwrap_or_else(|e| Args::command().error(ErrorKind::ValueValidation, e).exit())
+}
+
+/// The receive URL with each `--prefix` as its path. The built-in paths are already taken, and
+/// HTTP.SYS refuses to register the same URL twice, so either is reported as a usage error.
+fn prefix_urls(receive_url: &Url, prefixes: &[String]) -> Vec<Url> {
+    const RESERVED: [&str; 4] = ["/test", "/kill", "/stats", "/echo"];
+
+    let mut urls: Vec<Url> = Vec::with_capacity(prefixes.len());
+    for prefix in prefixes {
+        let mut url = receive_url.clone();
+        url.set_path(prefix);
+
+        let path = url.path().trim_end_matches('/');
+        let message = if RESERVED.contains(&path) {
+            format!("--prefix {prefix} is already served by the server")
+        } else if urls.iter().any(|u| u.path().trim_end_matches('/') == path) {
+            format!("--prefix {prefix} is given more than once")
+        } else {
+            urls.push(url);
+            continue;
+        };
+        Args::command()
+            .error(ErrorKind::ValueValidation, message)
+            .exit();
+    }
+    urls
+}
+
+/// Measures round trips to a UDP echo server and prints them like the client's results.
+/// Lost datagrams are counted as failures.
+fn measure_udp(target: &str, addr: SocketAddr, ping: &UdpPingOptions, format: OutputFormat) {
+    validate_measure_config(&ping.measure);
+    if ping.size > udp::MAX_DATAGRAM {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--size must be at most 65507 bytes",
+            )
+            .exit();
+    }
+
+    let pinger = UdpPinger::new(addr, ping.size, ping.timeout).expect("failed to open UDP socket");
+    if format == OutputFormat::Text {
+        let size = format_size(pinger.payload_size() as u64);
+        println!("Sending {size} datagrams to: {target} ({addr})");
+    }
+
+    // Datagrams go one at a time, so the lock is never contended
+    let pinger = Mutex::new(pinger);
+    let latency = measure_latency_concurrent(&ping.measure, 1, || {
+        let mut pinger = pinger.lock().unwrap_or_else(|e| e.into_inner());
+        pinger.ping()
+    });
+
+    let failures = latency.failures;
+
+    match format {
+        OutputFormat::Text => {
+            print_latency(&latency);
+            if ping.measure.report_warmup {
+                print_warmup(&latency);
+            }
+            if ping.measure.histogram {
+                print_histogram(&latency);
+            }
+            println!("Successes: {}", latency.iterations - failures);
+            println!("Lost: {failures}");
+        }
+        OutputFormat::Json => {
+            print_json(&ClientReport::from_latency(target, &latency, &ping.measure));
+        }
+    }
+
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// The compressed size of a body as a percentage of its original size.
+fn compressed_percent(compressed: usize, original: usize) -> f64 {
+    compressed as f64 * 100.0 / original.max(1) as f64
+}
+
+/// Describes each measurement whose p99 or mean latency is over its SLO, naming the
+/// measurement when there are several.
+fn slo_violations(
+    latencies: &[(&str, LatencyMeasurement)],
+    p99: Option<Duration>,
+    mean: Option<Duration>,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+    for (name, latency) in latencies {
+        let prefix = if latencies.len() > 1 {
+            format!("{name} ")
+        } else {
+            String::new()
+        };
+        for (metric, slo, value) in [("p99", p99, latency.p99), ("mean", mean, latency.latency)] {
+            if let Some(slo) = slo.filter(|slo| value > *slo) {
+                violations.push(format!(
+                    "{prefix}{metric} latency {value:?} is over {slo:?}"
+                ));
+            }
+        }
+    }
+    violations
+}
+
+/// Resolves `host:port` once up front, so that DNS isn't part of the measured connect time.
+fn resolve_target(target: &str) -> SocketAddr {
+    let resolved = target
+        .to_socket_addrs()
+        .map_err(|e| format!("can't resolve '{target}': {e}"))
+        .and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| format!("'{target}' resolved to no addresses"))
+        });
+    resolved.unwrap_or_else(|e| Args::command().error(ErrorKind::ValueValidation, e).exit())
 }
 
 fn is_valid_url(url: &str) -> Result<Url, String> {
     Url::parse(url).map_err(|error| error.to_string())
 }
 
+/// Logs this crate at the level `-v` asks for and everything else at warnings only, as the HTTP
+/// libraries' debug output would drown it out. `RUST_LOG`, when set, is used instead.
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => builder.parse_filters(&filters),
+        Err(_) => builder
+            .filter_level(LevelFilter::Warn)
+            .filter_module(env!("CARGO_CRATE_NAME"), level),
+    };
+    builder.init();
+}
+
 fn main() {
     let args = Args::parse();
+    init_logging(args.verbose);
 
     match &args.command {
-        Mode::Server { receive_url } => {
-            println!("Server running on {receive_url}/test/");
+        Mode::Server {
+            receive_url,
+            workers,
+            serve_file,
+            response_size,
+            delay,
+            jitter,
+            jitter_dist,
+            prefixes,
+            drain_timeout,
+            uds,
+        } => {
             let mut server = Server::new();
+            let receive_url = &server.listen(receive_url);
+            info!("Server running on {receive_url}/test/");
+            println!(
+                "{LISTENING_PREFIX}{}",
+                receive_url.port_or_known_default().unwrap_or(80)
+            );
+            let prefix_urls = prefix_urls(receive_url, prefixes);
+            for url in &prefix_urls {
+                info!("Also answering {url}");
+            }
+            server.set_workers(*workers as usize);
+            server.set_drain_timeout(*drain_timeout);
+            if let Some(path) = uds {
+                #[cfg(unix)]
+                {
+                    info!("Also answering on unix socket {}", path.display());
+                    server.set_uds_path(path.clone());
+                }
+                #[cfg(not(unix))]
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("can't listen on {}: --uds needs Unix", path.display()),
+                    )
+                    .exit();
+            }
             let test_url = {
                 let mut url = receive_url.clone();
                 url.set_path("/test");
@@ -89,302 +985,2415 @@ fn main() {
                 url.set_path("/kill");
                 url
             };
-            let handlers: Vec<(&Url, fn(&str) -> (String, bool))> = vec![
-                (&test_url, |_| ("OK".to_string(), false)),
-                (&kill_url, |_| ("OK".to_string(), true)),
+            let stats_url = {
+                let mut url = receive_url.clone();
+                url.set_path("/stats");
+                url
+            };
+            let echo_url = {
+                let mut url = receive_url.clone();
+                url.set_path("/echo");
+                url
+            };
+            server.set_stats_url(stats_url);
+            server.set_echo_url(echo_url);
+            let test_handler: Handler = match (serve_file, response_size) {
+                (Some(path), _) => {
+                    // Read once up front so every request is answered from memory
+                    let contents = std::fs::read(path).expect("failed to read file to serve");
+                    info!(
+                        "Serving {} ({})",
+                        path.display(),
+                        format_size(contents.len() as u64)
+                    );
+                    let path = path.clone();
+                    Box::new(move |_| HandlerResponse::file(&path, contents.clone()))
+                }
+                (None, Some(size)) => {
+                    info!("Streaming {} responses", format_size(*size as u64));
+                    let body = StreamedBody::new(*size);
+                    Box::new(move |_| HandlerResponse::streamed(body.clone()))
+                }
+                (None, None) => Box::new(|_| HandlerResponse::ok("OK")),
+            };
+            let test_handler: Arc<Handler> = match (*delay, *jitter) {
+                (delay, Some(jitter)) => {
+                    let (mean, distribution) = (delay.unwrap_or_default(), *jitter_dist);
+                    Arc::new(Box::new(move |request| HandlerResponse {
+                        delay: jittered_delay(&mut rand::thread_rng(), distribution, mean, jitter),
+                        ..test_handler(request)
+                    }))
+                }
+                (Some(delay), None) => Arc::new(Box::new(move |request| HandlerResponse {
+                    delay,
+                    ..test_handler(request)
+                })),
+                (None, None) => Arc::new(test_handler),
+            };
+            let kill_handler: HandlerFn = |_| HandlerResponse {
+                is_kill: true,
+                ..HandlerResponse::ok("OK")
+            };
+            // Every prefix shares the /test handler, but is registered and counted on its own
+            let shared_handler = |handler: &Arc<Handler>| -> Handler {
+                let handler = handler.clone();
+                Box::new(move |request| handler(request))
+            };
+            let mut handlers: Vec<(&Url, Handler)> = vec![
+                (&test_url, shared_handler(&test_handler)),
+                (&kill_url, Box::new(kill_handler)),
             ];
+            for url in &prefix_urls {
+                handlers.push((url, shared_handler(&test_handler)));
+            }
             server.define_handlers(handlers);
+            // The URLs are registered by now, so requests are answered from here on
+            println!("{READY_LINE}");
             server.wait();
         }
         Mode::Client {
             send_url,
             proxy_url,
+            proxy_user,
+            proxy_pass,
+            method,
+            body,
+            body_file,
+            payload_size,
+            payload_pattern,
+            compress_body,
+            concurrency,
+            warm_connections,
+            compare_keepalive,
+            compare_proxy,
+            rate,
+            duration,
+            count,
+            csv,
+            repeat,
+            stream,
+            quiet,
+            save,
+            plot,
+            trend_plot,
+            trend_window,
+            trend_percentiles,
+            prom_out,
+            slo_p99,
+            slo_mean,
+            options,
+            measure,
         } => {
-            println!("Client sending to: {send_url}");
-            println!("Validate SSL certificates: {}", !args.no_validate_certs);
+            validate_measure_config(measure);
+            validate_client_options(options);
+            if rate.is_some_and(|rate| !rate.is_finite() || rate < MIN_RATE) {
+                Args::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("--rate must be at least {MIN_RATE}"),
+                    )
+                    .exit();
+            }
+            if *compress_body && body.is_none() && body_file.is_none() && payload_size.is_none() {
+                Args::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--compress-body needs --body, --body-file or --payload-size",
+                    )
+                    .exit();
+            }
 
-            let average_latency = measure_latency(|| {
-                let _ = send_get_request(send_url, proxy_url, args.no_validate_certs);
-            });
+            // Streamed lines are kept apart from the text header so they can be parsed as NDJSON
+            let print_header = args.format == OutputFormat::Text && !*stream && !*quiet;
+            if print_header {
+                println!("Client sending to: {send_url}");
+                println!("Validate SSL certificates: {}", !args.no_validate_certs);
+                if *concurrency > 1 {
+                    println!("Concurrency: {concurrency}");
+                }
+                if let Some(rate) = rate {
+                    println!("Target rate: {rate} requests per second");
+                }
+            }
 
-            print_latency(&average_latency);
-        }
-        Mode::Echo {
-            send_url,
-            proxy_url,
-        } => {
-            println!("Client sending to: {send_url}");
-            println!("Validate SSL certificates: {}", !args.no_validate_certs);
+            let proxy_url = proxied_url(proxy_url, proxy_user, proxy_pass);
+            let body = match (body, body_file) {
+                (Some(body), _) => Some(body.clone().into_bytes()),
+                (None, Some(path)) => {
+                    let contents = std::fs::read(path).expect("failed to read body file");
+                    if print_header {
+                        println!(
+                            "Body file: {} ({})",
+                            path.display(),
+                            format_size(contents.len() as u64)
+                        );
+                    }
+                    Some(contents)
+                }
+                (None, None) => payload_size.map(|size| {
+                    generate_payload(&mut StdRng::from_entropy(), *payload_pattern, size)
+                }),
+            };
+            // Compressed once up front, and sent as-is by every request
+            let gzip_options;
+            let (body, options) = match body {
+                Some(body) if *compress_body => {
+                    let compressed = gzip_body(&body);
+                    if print_header {
+                        println!(
+                            "Compressed body: {} to {} ({:.1}%)",
+                            format_size(body.len() as u64),
+                            format_size(compressed.len() as u64),
+                            compressed_percent(compressed.len(), body.len())
+                        );
+                    }
+                    gzip_options = options.with_gzip_body();
+                    (Some(compressed), &gzip_options)
+                }
+                body => (body, options),
+            };
+            let method = Method::from(*method);
 
-            let start_time = Instant::now();
-            let result = send_get_request(send_url, proxy_url, args.no_validate_certs);
-            let latency = start_time.elapsed();
-            let mut response_size = 0;
+            // Measured once reusing connections and once opening a new one for every request, or
+            // once straight to the URL and once through the proxy
+            let series = if *compare_keepalive {
+                vec![
+                    (
+                        "Keep-alive",
+                        ClientOptions {
+                            no_keepalive: false,
+                            ..options.clone()
+                        },
+                        proxy_url.clone(),
+                    ),
+                    (
+                        "New connection",
+                        ClientOptions {
+                            no_keepalive: true,
+                            ..options.clone()
+                        },
+                        proxy_url.clone(),
+                    ),
+                ]
+            } else if *compare_proxy {
+                vec![
+                    ("direct", options.clone(), None),
+                    ("proxy", options.clone(), proxy_url.clone()),
+                ]
+            } else {
+                vec![("Request", options.clone(), proxy_url.clone())]
+            };
+            let mut measurements = Vec::new();
+            let mut latencies = Vec::new();
+            let mut any_failed = false;
 
-            println!("============================================================");
+            for (index, (name, options, proxy_url)) in series.iter().enumerate() {
+                if series.len() > 1 && args.format == OutputFormat::Text && !*quiet {
+                    if index > 0 {
+                        println!();
+                    }
+                    println!("{name}:");
+                }
+                let client = build_client(proxy_url, !args.no_validate_certs, options)
+                    .expect("failed to build HTTP client");
+                // The schedule runs on tokio timers, so a fixed rate sends from the async client
+                let async_client = rate.map(|_| {
+                    build_async_client(proxy_url, !args.no_validate_certs, options)
+                        .expect("failed to build HTTP client")
+                });
 
-            match result {
-                Ok(value) => {
-                    println!("{}", value);
-                    response_size = value.len();
+                // Calls made before the measured ones. Only the first run warms up, once per
+                // worker, except at a fixed rate where the first calls overall are the warmups
+                let warmups = match rate {
+                    Some(_) => measure.warmup,
+                    None => measure.warmup * *concurrency as usize,
+                };
+                let retry_policy = options.retry_policy();
+                let retries = AtomicUsize::new(0);
+                let first_failure = FirstFailure::new(*concurrency as usize);
+                let record = |attempt: &Retried<HttpResponse, Box<dyn std::error::Error>>,
+                              status: Option<u16>| {
+                    if *stream {
+                        let ts = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |since| since.as_millis());
+                        print_json(&RequestEvent {
+                            name,
+                            payload_size: body.as_ref().map_or(0, |b| b.len() as u64),
+                            ts,
+                            latency_ns: attempt.attempt_latency.as_nanos(),
+                            status,
+                            ok: attempt.result.is_ok(),
+                            warmup: util::is_warmup(),
+                        });
+                    }
+                    retries.fetch_add(attempt.retries as usize, Ordering::Relaxed);
+                    first_failure.record(&attempt.result);
+                };
+                let send = || {
+                    let status = Cell::new(None);
+                    let attempt = with_retries(&retry_policy, || {
+                        status.set(None);
+                        send_request(&client, method.clone(), send_url, body.as_deref()).and_then(
+                            |response| {
+                                status.set(Some(response.status.as_u16()));
+                                check_status(response, options.expect_status)
+                            },
+                        )
+                    });
+                    record(&attempt, status.get());
+                    attempt
+                };
+                let send_async = || {
+                    let client = async_client
+                        .as_ref()
+                        .expect("--rate builds an async client");
+                    let (method, body, status) = (&method, &body, Cell::new(None));
+                    let (retry_policy, record) = (&retry_policy, &record);
+                    async move {
+                        let attempt = with_retries_async(retry_policy, || async {
+                            status.set(None);
+                            send_request_async(client, method.clone(), send_url, body.as_deref())
+                                .await
+                                .and_then(|response| {
+                                    status.set(Some(response.status.as_u16()));
+                                    check_status(response, options.expect_status)
+                                })
+                        })
+                        .await;
+                        record(&attempt, status.get());
+                        attempt
+                    }
+                };
+
+                // Not counted or streamed, as these only open connections for the measurement
+                if *warm_connections {
+                    util::warm_connections(*concurrency as usize, &|| {
+                        send_request(&client, method.clone(), send_url, body.as_deref())
+                    });
                 }
-                Err(e) => eprintln!("Error: {}", e),
-            };
 
-            println!("============================================================");
-            println!("Latency: {:?}", latency);
-            println!("Response Size: {} chars", response_size);
-        }
-        Mode::Test => {
-            println!("Test mode");
-            let server_exe = run_this_exe_as_server();
+                let runtime = rate.map(|_| {
+                    tokio::runtime::Runtime::new().expect("failed to start tokio runtime")
+                });
+                let (runs, queueing_runs): (Vec<_>, Vec<_>) = (0..*repeat)
+                    .map(|run| {
+                        // The connections are already open after the first run, so only it warms up
+                        let measure = if run == 0 {
+                            measure.clone()
+                        } else {
+                            MeasureConfig {
+                                warmup: 0,
+                                ..measure.clone()
+                            }
+                        };
+                        let concurrency = *concurrency as usize;
+                        match (count, rate, &runtime, duration) {
+                            (Some(count), ..) => (
+                                measure_latency_count(&measure, *count as usize, concurrency, send),
+                                None,
+                            ),
+                            (_, Some(rate), Some(runtime), _) => {
+                                let measured = runtime.block_on(measure_latency_at_rate(
+                                    &measure,
+                                    *rate,
+                                    *duration,
+                                    concurrency,
+                                    send_async,
+                                ));
+                                (measured.service, Some(measured.queueing))
+                            }
+                            (_, _, _, Some(duration)) => (
+                                measure_latency_for(&measure, *duration, concurrency, send),
+                                None,
+                            ),
+                            _ => (
+                                measure_latency_concurrent(&measure, concurrency, send),
+                                None,
+                            ),
+                        }
+                    })
+                    .unzip();
+                let average_latency = combine_runs(&runs);
+                let queueing: Vec<_> = queueing_runs.into_iter().flatten().collect();
+                let queueing = (!queueing.is_empty()).then(|| combine_runs(&queueing));
 
-            println!("Server process started");
-            println!("Calling server multiple times to measure latency");
+                if let Some(csv) = csv {
+                    let payload_size = body.as_ref().map_or(0, |b| b.len() as u64);
+                    write_csv(csv, &[(payload_size, &average_latency)])
+                        .expect("failed to write CSV");
+                }
 
-            thread::sleep(Duration::from_millis(100));
+                // Counted by each worker, which leaves its own warmups out
+                let failures = average_latency.failures;
+                let successes = average_latency.iterations - failures;
+                let retries = retries.into_inner();
 
-            let send_url = server_exe.format_req_url("/test/");
-            let mut measurements = Vec::<Measurement>::new();
-            let mut payload_size = 1024; // Initial payload size
-            let target_size = 8 * 1024 * 1024; // 8 MB
-
-            while payload_size <= target_size {
-                let random_data = generate_random_payload(payload_size);
-                let latency_result = measure_latency(|| {
-                    task::block_in_place(|| {
-                        let _ = send_post_request(&send_url, &None, args.no_validate_certs, &random_data);                        
-                    })
-                });
+                match args.format {
+                    _ if *quiet => {}
+                    OutputFormat::Text => {
+                        if runs.len() > 1 {
+                            print_runs(&runs);
+                        }
+                        print_latency(&average_latency);
+                        if measure.report_warmup {
+                            print_warmup(&average_latency);
+                        }
+                        if measure.histogram {
+                            print_histogram(&average_latency);
+                        }
+                        if let Some(queueing) = &queueing {
+                            print_queueing(queueing);
+                        }
+                        println!("Successes: {successes}");
+                        println!("Failures: {failures}");
+                        println!("Retries: {retries}");
+                    }
+                    OutputFormat::Json => {
+                        let report = ClientReport {
+                            run_mean_latency_ns: (runs.len() > 1)
+                                .then(|| runs.iter().map(|r| r.latency.as_nanos()).collect()),
+                            concurrency: *concurrency,
+                            target_rate: *rate,
+                            queue_delay_mean_ns: queueing.as_ref().map(|q| q.latency.as_nanos()),
+                            queue_delay_p99_ns: queueing.as_ref().map(|q| q.p99.as_nanos()),
+                            keepalive: Some(!options.no_keepalive),
+                            proxied: Some(proxy_url.is_some()),
+                            retries,
+                            ..ClientReport::from_latency(
+                                send_url.as_str(),
+                                &average_latency,
+                                measure,
+                            )
+                        };
+                        print_json(&report);
+                    }
+                }
 
                 measurements.push(Measurement {
-                    name: &"Request",
-                    latency: latency_result.latency.as_nanos() as u64,
-                    payload_size : payload_size as u64,
+                    name: name.to_string(),
+                    latency: average_latency.latency.as_nanos() as u64,
+                    payload_size: body.as_ref().map_or(0, |b| b.len() as u64),
+                    min_latency: Some(average_latency.min.as_nanos() as u64),
+                    max_latency: Some(average_latency.max.as_nanos() as u64),
+                    first_byte: None,
                 });
+                latencies.push((*name, average_latency));
+                any_failed |= failures > 0;
 
-                println!("Average latency: {:?} : size {}", latency_result.latency, format_size(payload_size as u64));               
-
-                payload_size += payload_size / 4; // Double the payload size for the next iteration
+                if measure.fail_fast && first_failure.report(warmups) {
+                    break;
+                }
             }
 
-            write_plot(
-                &measurements,
-                "Same Machine HTTP requests to HTTP-SYS",
-                "Average MS",
-                "request-latency.svg",
-            )
-            .expect("failed to plot");
-        }
-    }
-}
-
-fn send_get_request(
-    url: &Url,
-    proxy_url: &Option<Url>,
-    validate_certs: bool,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = match proxy_url {
-        Some(proxy_url) => {
-            let proxy = Proxy::http(proxy_url.as_str())?;
-            Client::builder()
-                .proxy(proxy)
-                .danger_accept_invalid_certs(validate_certs)
-                .build()?
-        }
-        None => Client::builder()
-            .danger_accept_invalid_certs(validate_certs)            
-            .build()?,
-    };
-
-    let res = client.get(url.as_str()).header("Cache-Control", "no-cache").send()?;
-    let body = res.text()?;
-    Ok(body)
-}
+            if latencies.len() > 1 && args.format == OutputFormat::Text && !*quiet {
+                println!();
+                print_side_by_side(&latencies);
+            }
 
-fn send_post_request(
-    url: &Url,
-    proxy_url: &Option<Url>,
-    validate_certs: bool,
-    random_data: &String,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = match proxy_url {
-        Some(proxy_url) => {
-            let proxy = Proxy::http(proxy_url.as_str())?;
-            Client::builder()
-                .proxy(proxy)
-                .danger_accept_invalid_certs(validate_certs)
-                .build()?
-        }
-        None => Client::builder()
-            .danger_accept_invalid_certs(validate_certs)
-            .build()?,
-    };
+            if let Some(save) = save {
+                save_measurements(save, &measurements).expect("failed to save measurements");
+            }
 
-    let res = client
-        .post(url.as_str())
-        .header("Cache-Control", "no-cache")
-        .body(random_data.clone())
-        .send()?;
+            if let Some(plot) = plot {
+                write_plot(
+                    &measurements,
+                    &format!("Latency of {send_url}"),
+                    PlotMetric::Latency.y_label(),
+                    plot,
+                    PlotMetric::Latency,
+                    PlotKind::Bar,
+                    (PLOT_WIDTH, PLOT_HEIGHT),
+                )
+                .expect("failed to plot");
+                if !*quiet {
+                    println!("Plot written to {plot}");
+                }
+            }
 
-    let body = res.text()?;
-    Ok(body)
-}
+            if let Some(trend_plot) = trend_plot {
+                let series: Vec<_> = latencies
+                    .iter()
+                    .map(|(name, latency)| {
+                        (
+                            *name,
+                            latency.timeline.iter().map(|call| call.latency).collect(),
+                        )
+                    })
+                    .collect();
+                write_timeseries_plot(
+                    &series,
+                    *trend_window as usize,
+                    *trend_percentiles,
+                    &format!("Latency over the run to {send_url}"),
+                    trend_plot,
+                    (PLOT_WIDTH, PLOT_HEIGHT),
+                )
+                .expect("failed to plot");
+                if !*quiet {
+                    println!("Trend plot written to {trend_plot}");
+                }
+            }
 
-fn generate_random_payload(data_size: usize) -> String {
-    // Generate random text data
-    let mut rng = thread_rng();
-    let random_data: String = (0..data_size)
-        .map(|_| Alphanumeric.sample(&mut rng))
-        .map(char::from)
-        .collect();
-    random_data
-}
+            if let Some(prom_out) = prom_out {
+                let series: Vec<_> = latencies
+                    .iter()
+                    .map(|(name, latency)| PromSeries {
+                        labels: vec![("name", name.to_string()), ("url", send_url.to_string())],
+                        latency,
+                    })
+                    .collect();
+                write_prometheus(prom_out, &series).expect("failed to write Prometheus metrics");
+            }
 
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
+            let violations = slo_violations(&latencies, *slo_p99, *slo_mean);
+            for violation in &violations {
+                eprintln!("SLO failed: {violation}");
+            }
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
+            // A non-zero exit lets the client act as a health check in CI
+            if any_failed || !violations.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Mode::Echo {
+            send_url,
+            proxy_url,
+            proxy_user,
+            proxy_pass,
+            options,
+        } => {
+            validate_client_options(options);
+            if args.format == OutputFormat::Text {
+                println!("Client sending to: {send_url}");
+                println!("Validate SSL certificates: {}", !args.no_validate_certs);
+            }
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+            let proxy_url = proxied_url(proxy_url, proxy_user, proxy_pass);
+            let client = build_raw_client(&proxy_url, !args.no_validate_certs, options)
+                .expect("failed to build HTTP client");
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
-    }
-}
+            let attempt = with_retries(&options.retry_policy(), || {
+                send_compressed_request(&client, send_url)
+            });
+            let latency = attempt.attempt_latency;
+            let result = attempt.result;
 
-#[cfg(test)]
-mod tests {
+            // Timed with a separate request, as the reqwest client can't report phases
+            let phases = if proxy_url.is_none() {
+                Some(measure_phases(send_url, args.no_validate_certs, options))
+            } else {
+                None
+            };
 
-    use super::*;
-    use crate::server::Server;
-    use std::{thread, time::Duration};
+            if args.format == OutputFormat::Json {
+                let phases = phases
+                    .as_ref()
+                    .and_then(|phases| phases.as_ref().ok())
+                    .map(PhaseReport::from);
+                let report = match &result {
+                    Ok(compressed) => EchoReport {
+                        url: send_url.as_str(),
+                        latency_ns: latency.as_nanos(),
+                        response_size: compressed.response.body.len(),
+                        wire_size: compressed.wire_size,
+                        content_encoding: compressed.content_encoding.clone(),
+                        status: Some(compressed.response.status.as_u16()),
+                        http_version: Some(format!("{:?}", compressed.response.version)),
+                        final_url: Some(compressed.response.url.to_string()),
+                        set_cookies: set_cookies(&compressed.response.headers),
+                        error: None,
+                        phases,
+                    },
+                    Err(e) => EchoReport {
+                        url: send_url.as_str(),
+                        latency_ns: latency.as_nanos(),
+                        response_size: 0,
+                        wire_size: 0,
+                        content_encoding: None,
+                        status: None,
+                        http_version: None,
+                        final_url: None,
+                        set_cookies: Vec::new(),
+                        error: Some(e.to_string()),
+                        phases,
+                    },
+                };
+                print_json(&report);
+                return;
+            }
 
-    #[test]
-    fn test_basic_request() {
-        let port_num = 1919;
-        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+            let mut response_size = 0;
+            let mut wire_size = 0;
+            let mut content_encoding = None;
 
-        let mut server = Server::new();
-        let handlers: Vec<(&Url, fn(&str) -> (String, bool))> =
-            vec![(&server_url, |_| ("OK".to_string(), false))];
+            if let Ok(compressed) = &result {
+                let response = &compressed.response;
+                println!("Status: {}", response.status);
+                println!("Version: {:?}", response.version);
+                println!("Final URL: {}", response.url);
+                for cookie in set_cookies(&response.headers) {
+                    println!("Set cookie: {cookie}");
+                }
+                for (name, value) in response.headers.iter() {
+                    // Cookies were printed above, in full even when they aren't ASCII
+                    if name != reqwest::header::SET_COOKIE {
+                        println!("{}: {}", name, value.to_str().unwrap_or("<binary>"));
+                    }
+                }
+            }
 
-        server.define_handlers(handlers);
+            println!("============================================================");
 
-        thread::sleep(Duration::from_millis(100));
+            match result {
+                Ok(compressed) => {
+                    println!("{}", compressed.response.body);
+                    response_size = compressed.response.body.len();
+                    wire_size = compressed.wire_size;
+                    content_encoding = compressed.content_encoding;
+                }
+                Err(e) => eprintln!("Error: {}", e),
+            };
 
-        let result = send_post_request(&server_url, &None, false, &"xxx".to_string()).unwrap();
-        assert_eq!(result, "OK");
+            println!("============================================================");
+            println!("Latency: {:?}", latency);
+            println!("Response Size: {} chars", response_size);
+            match content_encoding {
+                Some(encoding) => println!("Wire Size: {} bytes ({})", wire_size, encoding),
+                None => println!("Wire Size: {} bytes", wire_size),
+            }
+            if attempt.retries > 0 {
+                println!("Retries: {}", attempt.retries);
+            }
 
-        server.kill();
-        server.wait();
-    }
-}
+            match phases {
+                Some(Ok(phases)) => print_phases(&phases),
+                Some(Err(e)) => eprintln!("Phase timing failed: {}", e),
+                None => println!("Phase timings are not measured through a proxy"),
+            }
+        }
+        Mode::Connect {
+            target,
+            concurrency,
+            timeout,
+            measure,
+        } => {
+            validate_measure_config(measure);
+            let addr = resolve_target(target);
 
-fn format_size(size_in_bytes: u64) -> String {
-    const KB: u64 = 1024;
-    const MB: u64 = KB * 1024;
+            if args.format == OutputFormat::Text {
+                println!("Connecting to: {target} ({addr})");
+                if *concurrency > 1 {
+                    println!("Concurrency: {concurrency}");
+                }
+            }
 
-    if size_in_bytes >= MB {
-        format!("{:.1}mb", size_in_bytes as f64 / MB as f64)
-    } else if size_in_bytes >= KB {
-        format!("{:.1}kb", size_in_bytes as f64 / KB as f64)
-    } else {
-        format!("{}b", size_in_bytes)
-    }
-}
+            let connect = || {
+                // The stream is dropped straight away, so each sample is a fresh connection
+                let stream = match timeout {
+                    Some(timeout) => TcpStream::connect_timeout(&addr, *timeout),
+                    None => TcpStream::connect(addr),
+                };
+                stream.map(drop)
+            };
+            let latency = measure_latency_concurrent(measure, *concurrency as usize, connect);
 
-const FONT: &str = "Fira Code";
-const PLOT_WIDTH: u32 = 800;
-const PLOT_HEIGHT: u32 = 400;
+            let failures = latency.failures;
 
+            match args.format {
+                OutputFormat::Text => {
+                    print_latency(&latency);
+                    if measure.report_warmup {
+                        print_warmup(&latency);
+                    }
+                    if measure.histogram {
+                        print_histogram(&latency);
+                    }
+                    println!("Successes: {}", latency.iterations - failures);
+                    println!("Failures: {failures}");
+                }
+                OutputFormat::Json => {
+                    let report = ClientReport {
+                        concurrency: *concurrency,
+                        ..ClientReport::from_latency(target, &latency, measure)
+                    };
+                    print_json(&report);
+                }
+            }
 
-pub struct Measurement<'a> {
-    pub name : &'a str,
-    pub latency: u64,
-    pub payload_size: u64, 
-}
+            if failures > 0 {
+                std::process::exit(1);
+            }
+        }
+        Mode::Pipeline {
+            send_url,
+            depth,
+            timeout,
+            uds,
+            measure,
+        } => {
+            validate_measure_config(measure);
+            let target = match uds {
+                #[cfg(unix)]
+                Some(path) => Target::Unix(path.clone()),
+                #[cfg(not(unix))]
+                Some(path) => Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("can't connect to {}: --uds needs Unix", path.display()),
+                    )
+                    .exit(),
+                None => {
+                    let host = send_url.host_str().unwrap_or("localhost");
+                    let port = send_url.port_or_known_default().unwrap_or(80);
+                    Target::Tcp(resolve_target(&format!("{host}:{port}")))
+                }
+            };
+            if args.format == OutputFormat::Text {
+                println!("Pipelining {depth} requests at a time to: {send_url} ({target})");
+            }
+            let pipeliner = Pipeliner::new(send_url, target, *depth as usize, *timeout)
+                .unwrap_or_else(|e| Args::command().error(ErrorKind::ValueValidation, e).exit());
 
-pub fn write_plot(
-    records: &Vec<Measurement>,
-    caption: &str,
-    y_label: &str,
-    path: &str,
-) -> Result<(), Box<dyn Error>> {
-    let mut groups: BTreeMap<&str, Vec<&Measurement>> = BTreeMap::new();
+            // Batches go one at a time on one connection, so the lock is never contended
+            let depth = pipeliner.depth();
+            let pipeliner = Mutex::new(pipeliner);
+            let latency = measure_latency_concurrent(measure, 1, || {
+                let mut pipeliner = pipeliner.lock().unwrap_or_else(|e| e.into_inner());
+                pipeliner.send_batch()
+            });
 
+            let failures = latency.failures;
 
-    for record in records.iter() {
-        let group = groups.entry(record.name).or_insert_with(Vec::new);
-        group.push(&record);
-    }
+            match args.format {
+                OutputFormat::Text => {
+                    print_latency(&latency);
+                    if measure.report_warmup {
+                        print_warmup(&latency);
+                    }
+                    if measure.histogram {
+                        print_histogram(&latency);
+                    }
+                    println!("Per request: {:?}", latency.latency / depth as u32);
+                    println!(
+                        "Pipelined requests per second: {:.1}",
+                        latency.requests_per_second * depth as f64
+                    );
+                    println!("Successful batches: {}", latency.iterations - failures);
+                    println!("Failed batches: {failures}");
+                }
+                OutputFormat::Json => {
+                    let report = ClientReport {
+                        requests_per_second: latency.requests_per_second * depth as f64,
+                        keepalive: Some(true),
+                        pipeline_depth: Some(depth),
+                        ..ClientReport::from_latency(send_url.as_str(), &latency, measure)
+                    };
+                    print_json(&report);
+                }
+            }
+
+            if failures > 0 {
+                std::process::exit(1);
+            }
+        }
+        Mode::Grpc {
+            send_url,
+            method,
+            body_file,
+            timeout,
+            measure,
+        } => {
+            validate_measure_config(measure);
+            let message = match body_file {
+                Some(path) => std::fs::read(path).unwrap_or_else(|e| {
+                    Args::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            format!("can't read {}: {e}", path.display()),
+                        )
+                        .exit()
+                }),
+                None => Vec::new(),
+            };
+            let host = send_url.host_str().unwrap_or("localhost");
+            let port = send_url.port_or_known_default().unwrap_or(80);
+            let addr = resolve_target(&format!("{host}:{port}"));
+            let client = GrpcClient::new(
+                send_url,
+                addr,
+                method,
+                &message,
+                args.no_validate_certs,
+                *timeout,
+            )
+            .unwrap_or_else(|e| Args::command().error(ErrorKind::ValueValidation, e).exit());
+            if args.format == OutputFormat::Text {
+                let size = format_size(message.len() as u64);
+                println!("Calling {} with a {size} message ({addr})", client.uri());
+            }
+
+            let runtime = tokio::runtime::Runtime::new().expect("failed to start tokio runtime");
+            let first_failure = FirstFailure::default();
+            let (client, first_failure) = (&client, &first_failure);
+            let call = || async move {
+                let result = client.call().await;
+                first_failure.record(&result);
+                result
+            };
+            let latency = runtime.block_on(measure_latency_async(measure, call, |_| {}));
+
+            let failures = latency.failures;
+
+            match args.format {
+                OutputFormat::Text => {
+                    print_latency(&latency);
+                    if measure.report_warmup {
+                        print_warmup(&latency);
+                    }
+                    if measure.histogram {
+                        print_histogram(&latency);
+                    }
+                    println!("Successful calls: {}", latency.iterations - failures);
+                    println!("Failed calls: {failures}");
+                }
+                OutputFormat::Json => {
+                    let report = ClientReport {
+                        keepalive: Some(true),
+                        ..ClientReport::from_latency(send_url.as_str(), &latency, measure)
+                    };
+                    print_json(&report);
+                }
+            }
+
+            // The first failure shows which status the server answered with
+            if failures > 0 {
+                first_failure.report(measure.warmup);
+                std::process::exit(1);
+            }
+        }
+        Mode::Udp { role } => match role {
+            UdpRole::Server { bind } => {
+                udp::serve(bind).expect("UDP echo server failed");
+            }
+            UdpRole::Client { target, ping } => {
+                let addr = resolve_target(target);
+                measure_udp(target, addr, ping, args.format);
+            }
+            UdpRole::Test { ping } => {
+                let server_exe = run_this_exe_as_udp_server();
+                info!("UDP echo server process started");
+
+                let target = format!("127.0.0.1:{}", server_exe.port);
+                let addr = resolve_target(&target);
+                measure_udp(&target, addr, ping, args.format);
+            }
+        },
+        Mode::Test {
+            min_size,
+            max_size,
+            growth,
+            output,
+            output_dir,
+            plot_width,
+            plot_height,
+            plot_title,
+            csv,
+            name,
+            save,
+            prom_out,
+            metric,
+            seed,
+            payload_pattern,
+            body_file,
+            quiet,
+            path,
+            verify_echo,
+            compress_body,
+            dry_run,
+            options,
+            measure,
+        } => {
+            validate_measure_config(measure);
+            validate_client_options(options);
+
+            if max_size < min_size {
+                Args::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        "--max-size must be >= --min-size",
+                    )
+                    .exit();
+            }
+            if *growth <= 1.0 {
+                Args::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        "--growth must be greater than 1.0",
+                    )
+                    .exit();
+            }
+            // Responses are read as text, which binary bytes don't survive
+            if *verify_echo && *payload_pattern == PayloadPattern::Binary {
+                Args::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--verify-echo can't check --payload-pattern binary",
+                    )
+                    .exit();
+            }
+
+            // A body file is sent as-is, so it is measured at its own size only
+            let body_file_size = body_file.as_ref().map(|path| {
+                std::fs::metadata(path)
+                    .expect("failed to read body file")
+                    .len() as usize
+            });
+            let sizes = match body_file_size {
+                Some(size) => vec![size],
+                None => payload_sizes(*min_size, *max_size, *growth),
+            };
+            if *dry_run {
+                print_schedule(&sizes, measure);
+                return;
+            }
+
+            println!("Test mode");
+            let server_exe = run_this_exe_as_server();
+
+            info!("Server process started");
+            println!("Calling server multiple times to measure latency");
+
+            let send_url = server_exe.format_req_url(path);
+            println!("Sending to: {send_url}");
+            let gzip_options;
+            let options = if *compress_body {
+                gzip_options = options.with_gzip_body();
+                &gzip_options
+            } else {
+                options
+            };
+            let client = build_async_client(&None, !args.no_validate_certs, options)
+                .expect("failed to build HTTP client");
+            let runtime = tokio::runtime::Runtime::new().expect("failed to start tokio runtime");
+            let mut measurements = Vec::<Measurement>::new();
+            let mut runs = Vec::new();
+            let file_body = body_file.as_ref().map(|path| {
+                let contents = std::fs::read(path).expect("failed to read body file");
+                println!(
+                    "Body file: {} ({})",
+                    path.display(),
+                    format_size(contents.len() as u64)
+                );
+                contents
+            });
+            let retry_policy = options.retry_policy();
+            let mismatches = AtomicUsize::new(0);
+            let retries = AtomicUsize::new(0);
+            let mut rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(*seed),
+                None => StdRng::from_entropy(),
+            };
+
+            for payload_size in sizes {
+                let random_data;
+                let payload = match &file_body {
+                    Some(contents) => contents.as_slice(),
+                    None => {
+                        random_data = generate_payload(&mut rng, *payload_pattern, payload_size);
+                        random_data.as_slice()
+                    }
+                };
+                // Compressed before the size is measured, so compressing isn't timed
+                let compressed;
+                let payload = if *compress_body {
+                    compressed = gzip_body(payload);
+                    compressed.as_slice()
+                } else {
+                    payload
+                };
+                let (client, send_url) = (&client, &send_url);
+                let (retry_policy, retries) = (&retry_policy, &retries);
+                let mismatches = &mismatches;
+                // Times to first byte and response sizes of the measured calls, which come after
+                // the warmups
+                let calls = AtomicUsize::new(0);
+                let first_bytes = Mutex::new(Vec::new());
+                let response_sizes = Mutex::new(Vec::new());
+                let (calls, first_bytes, response_sizes) = (&calls, &first_bytes, &response_sizes);
+                let first_failure = FirstFailure::default();
+                let first_failure = &first_failure;
+                let progress = size_progress(*quiet, measure, payload_size);
+                let send = || async move {
+                    let call = calls.fetch_add(1, Ordering::Relaxed);
+                    let attempt = with_retries_async(retry_policy, || async move {
+                        let response =
+                            send_request_async(client, Method::POST, send_url, Some(payload))
+                                .await
+                                .and_then(|response| {
+                                    check_status(response, options.expect_status)
+                                })?;
+                        if !*verify_echo {
+                            return Ok(response);
+                        }
+                        check_echo(response, payload).inspect_err(|_| {
+                            if call >= measure.warmup {
+                                mismatches.fetch_add(1, Ordering::Relaxed);
+                            }
+                        })
+                    })
+                    .await;
+                    retries.fetch_add(attempt.retries as usize, Ordering::Relaxed);
+                    first_failure.record(&attempt.result);
+                    match &attempt.result {
+                        Ok(response) if call >= measure.warmup => {
+                            first_bytes.lock().unwrap().extend(response.first_byte);
+                            response_sizes.lock().unwrap().push(response.body_len);
+                        }
+                        _ => {}
+                    }
+                    attempt
+                };
+                let latency_result =
+                    runtime.block_on(measure_latency_async(measure, send, |done| {
+                        progress.set_position(done as u64)
+                    }));
+                progress.finish_and_clear();
+                let first_bytes = std::mem::take(&mut *first_bytes.lock().unwrap());
+                let first_byte = (!first_bytes.is_empty())
+                    .then(|| first_bytes.iter().sum::<Duration>() / first_bytes.len() as u32);
+
+                measurements.push(Measurement {
+                    name: name.clone(),
+                    latency: latency_result.latency.as_nanos() as u64,
+                    payload_size: payload_size as u64,
+                    min_latency: Some(latency_result.min.as_nanos() as u64),
+                    max_latency: Some(latency_result.max.as_nanos() as u64),
+                    first_byte: first_byte.map(|first_byte| first_byte.as_nanos() as u64),
+                });
 
-    let resolution = (PLOT_WIDTH, PLOT_HEIGHT);
-    let root = SVGBackend::new(&path, resolution).into_drawing_area();
+                let throughput = throughput_mb_per_sec(payload_size as u64, latency_result.latency);
+                println!(
+                    "Average latency: {:?} : size {} : {}",
+                    latency_result.latency,
+                    format_size(payload_size as u64),
+                    format_throughput(throughput)
+                );
+                // The fastest call is the floor the transport allows, steadier than the mean
+                println!(
+                    "Min latency: {:?} : max {:?}",
+                    latency_result.min, latency_result.max
+                );
+                if let Some(first_byte) = first_byte {
+                    println!("Time to first byte: {first_byte:?}");
+                }
+                if *compress_body {
+                    println!(
+                        "Compressed size: {} bytes ({:.1}% of the payload)",
+                        payload.len(),
+                        compressed_percent(payload.len(), payload_size)
+                    );
+                }
+                // A spread here means the server truncated or padded some of its responses
+                let response_sizes = std::mem::take(&mut *response_sizes.lock().unwrap());
+                if let (Some(min), Some(max)) =
+                    (response_sizes.iter().min(), response_sizes.iter().max())
+                {
+                    // In bytes, as a rounded size would hide a response a few bytes short
+                    let total = response_sizes.iter().sum::<usize>();
+                    let mean = total as f64 / response_sizes.len() as f64;
+                    println!("Response size: min {min} : mean {mean:.1} : max {max} bytes");
+                }
+                if measure.report_warmup {
+                    print_warmup(&latency_result);
+                }
+                if measure.histogram {
+                    print_histogram(&latency_result);
+                }
 
-    root.fill(&WHITE)?;
+                runs.push((payload_size as u64, latency_result));
 
-    
-    let y_min = records.iter().map(|m| m.latency).min().unwrap();
-    let y_max = records.iter().map(|m| m.latency).max().unwrap();
-    let y_diff = y_max - y_min;
-    let y_padding = (y_diff / 10).min(y_min);
+                if measure.fail_fast && first_failure.report(measure.warmup) {
+                    eprintln!("Stopped at size {}", format_size(payload_size as u64));
+                    drop(server_exe);
+                    std::process::exit(1);
+                }
+            }
 
-    let x_min = records.iter().map(|m| m.payload_size).min().unwrap();
-    let x_max = records.iter().map(|m| m.payload_size).max().unwrap();
+            let failures: usize = runs.iter().map(|(_, latency)| latency.failures).sum();
+            println!("Failures: {failures}");
+            if *verify_echo {
+                println!("Echo mismatches: {}", mismatches.into_inner());
+            }
+            println!("Retries: {}", retries.into_inner());
 
-    
-    let mut chart = ChartBuilder::on(&root)
-        .margin(10)
-        .caption(caption, (FONT, 20))
-        .set_label_area_size(LabelAreaPosition::Left, 70)
-        .set_label_area_size(LabelAreaPosition::Right, 70)
-        .set_label_area_size(LabelAreaPosition::Bottom, 40)
-        .build_cartesian_2d(1..x_max, y_min - y_padding..y_max + y_padding)?;
+            if let Some(csv) = csv {
+                let runs: Vec<_> = runs.iter().map(|(size, m)| (*size, m)).collect();
+                write_csv(csv, &runs).expect("failed to write CSV");
+            }
 
-    chart
-        .configure_mesh()
-        .disable_y_mesh()
-        .x_label_formatter(&|v| format_size(*v ))
-        .y_label_formatter(&|v| format!("{:.1} ms", *v as f64 / 1_000_000.0))
-        .x_labels(20)
-        .y_labels(20)
-        .y_desc(y_label)
-        .x_desc("Size")
-        .draw()?;
+            if let Some(save) = save {
+                save_measurements(save, &measurements).expect("failed to save measurements");
+            }
 
-    for records in groups.values() {
-        let color = BLUE;
-        chart
-            .draw_series(LineSeries::new(
-                records
+            if let Some(prom_out) = prom_out {
+                let series: Vec<_> = runs
                     .iter()
-                    .map(|record| (record.payload_size, record.latency)),
-                color,
-            ))?
-            .label(records[0].name)
-            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
-    }
-
-    chart
-        .configure_series_labels()
-        .position(SeriesLabelPosition::UpperLeft)
-        .label_font((FONT, 13))
-        .background_style(WHITE.mix(0.8))
-        .border_style(BLACK)
-        .draw()?;
-
-    Ok(())
+                    .map(|(size, latency)| PromSeries {
+                        labels: vec![("name", name.clone()), ("payload_size", size.to_string())],
+                        latency,
+                    })
+                    .collect();
+                write_prometheus(prom_out, &series).expect("failed to write Prometheus metrics");
+            }
+
+            let output = match output_dir {
+                Some(dir) => {
+                    std::fs::create_dir_all(dir).expect("failed to create output directory");
+                    let (plot, csv, json) =
+                        timestamped_outputs(dir, output, chrono::Local::now().naive_local());
+                    let runs: Vec<_> = runs.iter().map(|(size, m)| (*size, m)).collect();
+                    write_csv(&csv, &runs).expect("failed to write CSV");
+                    save_measurements(&json, &measurements).expect("failed to save measurements");
+                    println!(
+                        "Measurements written to {} and {}",
+                        csv.display(),
+                        json.display()
+                    );
+                    plot.to_string_lossy().into_owned()
+                }
+                None => output.clone(),
+            };
+
+            write_plot(
+                &measurements,
+                plot_title,
+                metric.y_label(),
+                &output,
+                *metric,
+                PlotKind::Line,
+                (*plot_width, *plot_height),
+            )
+            .expect("failed to plot");
+            if output_dir.is_some() {
+                println!("Plot written to {output}");
+            }
+        }
+        Mode::Plot {
+            inputs,
+            output,
+            caption,
+            metric,
+            kind,
+        } => {
+            let mut measurements = Vec::new();
+            for input in inputs {
+                measurements.extend(load_measurements(input).expect("failed to load measurements"));
+            }
+
+            write_plot(
+                &measurements,
+                caption,
+                metric.y_label(),
+                output,
+                *metric,
+                *kind,
+                (PLOT_WIDTH, PLOT_HEIGHT),
+            )
+            .expect("failed to plot");
+            println!("Plot written to {output}");
+        }
+        Mode::Aggregate {
+            input,
+            output,
+            caption,
+            metric,
+            kind,
+            save,
+        } => {
+            let groups = if input.as_os_str() == "-" {
+                aggregate(std::io::stdin().lock())
+            } else {
+                std::fs::File::open(input)
+                    .map_err(Into::into)
+                    .and_then(|file| aggregate(std::io::BufReader::new(file)))
+            };
+            let groups = groups.unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {e}", input.display());
+                std::process::exit(1);
+            });
+
+            // A group of failures alone has no latency to show, and would plot as zero
+            let groups: Vec<_> = groups
+                .into_iter()
+                .filter(|group| {
+                    let empty = group.latency.samples.is_empty();
+                    if empty {
+                        warn!(
+                            "Skipping {} at size {}: every sample failed",
+                            group.name,
+                            format_size(group.payload_size)
+                        );
+                    }
+                    !empty
+                })
+                .collect();
+
+            let mut measurements = Vec::new();
+            for (index, group) in groups.iter().enumerate() {
+                if index > 0 {
+                    println!();
+                }
+                println!(
+                    "{} : size {} : {} samples",
+                    group.name,
+                    format_size(group.payload_size),
+                    group.latency.samples.len()
+                );
+                print_latency(&group.latency);
+                measurements.push(Measurement {
+                    name: group.name.clone(),
+                    latency: group.latency.latency.as_nanos() as u64,
+                    payload_size: group.payload_size,
+                    min_latency: Some(group.latency.min.as_nanos() as u64),
+                    max_latency: Some(group.latency.max.as_nanos() as u64),
+                    first_byte: None,
+                });
+            }
+
+            if let Some(save) = save {
+                save_measurements(save, &measurements).expect("failed to save measurements");
+            }
+            if let Some(output) = output {
+                write_plot(
+                    &measurements,
+                    caption,
+                    metric.y_label(),
+                    output,
+                    *metric,
+                    *kind,
+                    (PLOT_WIDTH, PLOT_HEIGHT),
+                )
+                .expect("failed to plot");
+                println!("Plot written to {output}");
+            }
+        }
+        Mode::Compare {
+            baseline,
+            current,
+            threshold,
+            output,
+        } => {
+            if !threshold.is_finite() || *threshold < 0.0 {
+                Args::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        "--threshold must be zero or more",
+                    )
+                    .exit();
+            }
+
+            let baseline = load_measurements(baseline).expect("failed to load baseline");
+            let current = load_measurements(current).expect("failed to load current run");
+            let changes = compare_runs(&baseline, &current, *threshold);
+            if changes.is_empty() {
+                println!("No points match by name and payload size");
+                std::process::exit(1);
+            }
+            print_comparison(&changes);
+
+            if let Some(output) = output {
+                // Both runs usually share a name, so each is relabelled to keep its own line
+                let relabel = |records: &[Measurement], run: &str| {
+                    records
+                        .iter()
+                        .map(|m| Measurement {
+                            name: format!("{} ({run})", m.name),
+                            ..m.clone()
+                        })
+                        .collect::<Vec<_>>()
+                };
+                let mut records = relabel(&baseline, "baseline");
+                records.extend(relabel(&current, "current"));
+                let regressed: Vec<&Measurement> = changes
+                    .iter()
+                    .filter(|change| change.regressed)
+                    .filter_map(|change| {
+                        let name = format!("{} (current)", change.name);
+                        records
+                            .iter()
+                            .find(|m| m.name == name && m.payload_size == change.payload_size)
+                    })
+                    .collect();
+                write_comparison_plot(
+                    &records,
+                    &regressed,
+                    "Baseline and current latency",
+                    output,
+                    (PLOT_WIDTH, PLOT_HEIGHT),
+                )
+                .expect("failed to plot");
+                println!("Plot written to {output}");
+            }
+
+            let regressions = changes.iter().filter(|change| change.regressed).count();
+            if regressions > 0 {
+                println!(
+                    "FAIL: {regressions} of {} points regressed more than {threshold}%",
+                    changes.len()
+                );
+                std::process::exit(1);
+            }
+            println!("PASS: no point regressed more than {threshold}%");
+        }
+    }
+}
+
+/// A progress bar for the measurement of one payload size, counting up to the most iterations
+/// the loop may run. Drawn on stderr, and hidden when `quiet` is set or stderr isn't a terminal.
+fn size_progress(quiet: bool, measure: &MeasureConfig, payload_size: usize) -> ProgressBar {
+    if quiet {
+        return ProgressBar::hidden();
+    }
+
+    let progress = ProgressBar::new(measure.max_iterations as u64);
+    if let Ok(style) = ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len}") {
+        progress.set_style(style.progress_chars("=> "));
+    }
+    progress.set_message(format!("size {}", format_size(payload_size as u64)));
+    progress
+}
+
+/// The payload sizes a test run measures, from `min` growing by `growth` each step until past
+/// `max`.
+fn payload_sizes(min: usize, max: usize, growth: f64) -> Vec<usize> {
+    let mut sizes = Vec::new();
+    let mut size = min;
+    while size <= max {
+        sizes.push(size);
+        // Always advance by at least one byte so small sizes with a low growth still progress
+        size = ((size as f64 * growth) as usize).max(size + 1);
+    }
+    sizes
+}
+
+/// Prints each payload size of a test run and the calls it may make, which depend on how soon
+/// latency stabilises.
+fn print_schedule(sizes: &[usize], measure: &MeasureConfig) {
+    println!("Payload sizes: {}", sizes.len());
+    for (step, size) in sizes.iter().enumerate() {
+        println!(
+            "{:>4}: {} ({size} bytes)",
+            step + 1,
+            format_size(*size as u64)
+        );
+    }
+    let calls = |iterations: usize| sizes.len() * (measure.warmup + iterations);
+    let bytes = |iterations: usize| {
+        sizes
+            .iter()
+            .map(|size| (*size * (measure.warmup + iterations)) as u64)
+            .sum::<u64>()
+    };
+    println!(
+        "Calls per size: {} warmup + {} to {} measured",
+        measure.warmup, measure.min_iterations, measure.max_iterations
+    );
+    println!(
+        "Total calls: {} to {} ({} to {} sent)",
+        calls(measure.min_iterations),
+        calls(measure.max_iterations),
+        format_size(bytes(measure.min_iterations)),
+        format_size(bytes(measure.max_iterations))
+    );
+}
+
+/// Prints cumulative phase timings, in the same order as curl's `-w` output.
+fn print_phases(phases: &PhaseTimings) {
+    println!("DNS lookup: {:?}", phases.dns);
+    println!("TCP connect: {:?}", phases.connect);
+    match phases.tls {
+        Some(tls) => println!("TLS handshake: {:?}", tls),
+        None => println!("TLS handshake: n/a"),
+    }
+    if let Some(version) = phases.tls_version {
+        println!("TLS version: {}", version.name());
+    }
+    println!("First byte: {:?}", phases.first_byte);
+    println!("Total: {:?}", phases.total);
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    match serde_json::to_string(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+/// A delay drawn from `distribution` around `mean`, on the scale of `jitter`. Delays can't be
+/// negative, so any drawn below zero are sent at once.
+fn jittered_delay<R: Rng>(
+    rng: &mut R,
+    distribution: JitterDistribution,
+    mean: Duration,
+    jitter: Duration,
+) -> Duration {
+    let offset = match distribution {
+        JitterDistribution::Uniform => rng.gen_range(-1.0..=1.0),
+        JitterDistribution::Normal => {
+            // Box-Muller, with the first uniform kept off zero so its log is finite
+            let u1 = 1.0 - rng.gen::<f64>();
+            let u2 = rng.gen::<f64>();
+            (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+        }
+    };
+    Duration::from_secs_f64((mean.as_secs_f64() + offset * jitter.as_secs_f64()).max(0.0))
+}
+
+/// Words the text pattern is made of.
+const LOREM_IPSUM: [&str; 24] = [
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+    "enim",
+    "ad",
+    "minim",
+    "veniam",
+    "quis",
+];
+
+fn generate_payload<R: Rng>(rng: &mut R, pattern: PayloadPattern, data_size: usize) -> Vec<u8> {
+    match pattern {
+        PayloadPattern::Random => (0..data_size).map(|_| Alphanumeric.sample(rng)).collect(),
+        PayloadPattern::Zeros => vec![0u8; data_size],
+        PayloadPattern::Text => {
+            let mut text = Vec::with_capacity(data_size + 16);
+            while text.len() < data_size {
+                text.extend_from_slice(LOREM_IPSUM[rng.gen_range(0..LOREM_IPSUM.len())].as_bytes());
+                text.push(b' ');
+            }
+            text.truncate(data_size);
+            text
+        }
+        PayloadPattern::Binary => {
+            let mut bytes = vec![0u8; data_size];
+            rng.fill_bytes(&mut bytes);
+            bytes
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::server::{Handler, Server};
+    use reqwest::StatusCode;
+    use std::sync::Arc;
+    use std::{thread, time::Duration};
+
+    /// Polls until `url`'s port accepts a connection, failing after a few seconds, rather than
+    /// sleeping for a fixed time that a loaded machine may overrun.
+    fn wait_until_listening(url: &Url) {
+        let addrs = url.socket_addrs(|| None).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let timeout = Duration::from_millis(100);
+        while !addrs
+            .iter()
+            .any(|addr| TcpStream::connect_timeout(addr, timeout).is_ok())
+        {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "{url} never started listening"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_basic_request() {
+        let port_num = 1919;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |request| {
+            assert_eq!(request.method, "POST");
+            assert!(request.url.ends_with("/nop/"));
+            assert_eq!(request.body, b"xxx");
+            HandlerResponse::ok("OK")
+        })];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::POST, &server_url, Some(b"xxx")).unwrap();
+        assert_eq!(result.body, "OK");
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_listen_on_free_port() {
+        let mut server = Server::new();
+        let url = server.listen(&Url::parse("http://localhost:0").unwrap());
+        assert_ne!(url.port(), Some(0));
+
+        let mut server_url = url.clone();
+        server_url.set_path("/free/");
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("free"))];
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::GET, &server_url, None).unwrap();
+        assert_eq!(result.body, "free");
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_handler_sees_request() {
+        let port_num = 1929;
+        let server_url = Url::parse(&format!("http://localhost:{}/check/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |request| {
+            if request.method == "POST" && request.body.is_empty() {
+                return HandlerResponse {
+                    status: 400,
+                    ..HandlerResponse::ok("empty body")
+                };
+            }
+            HandlerResponse::ok(request.header("x-reply").unwrap_or("none"))
+        })];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::POST, &server_url, None).unwrap();
+        assert_eq!(result.status, StatusCode::BAD_REQUEST);
+        let result = send_request(&client, Method::POST, &server_url, Some(b"xxx")).unwrap();
+        assert_eq!(result.status, StatusCode::OK);
+        assert_eq!(result.body, "none");
+
+        let options = ClientOptions {
+            headers: vec![client::parse_header("X-Reply: hello").unwrap()],
+            ..ClientOptions::default()
+        };
+        let client = build_client(&None, true, &options).unwrap();
+        let result = send_request(&client, Method::GET, &server_url, None).unwrap();
+        assert_eq!(result.body, "hello");
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_echo_request() {
+        let port_num = 1923;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+        let echo_url = Url::parse(&format!("http://localhost:{}/echo/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        server.set_echo_url(echo_url.clone());
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("OK"))];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::POST, &echo_url, Some(b"xxx")).unwrap();
+        assert_eq!(result.body, "xxx");
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_streamed_response() {
+        let port_num = 1924;
+        let server_url = Url::parse(&format!("http://localhost:{}/big/", port_num)).unwrap();
+
+        // Three full chunks and a partial one
+        let size = 200_000;
+        let body = StreamedBody::new(size);
+        assert_eq!(body.chunks().count(), 4);
+
+        let mut server = Server::new();
+        let handlers: Vec<(&Url, Handler)> = vec![(
+            &server_url,
+            Box::new(move |_| HandlerResponse::streamed(body.clone())),
+        )];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::GET, &server_url, None).unwrap();
+        assert_eq!(result.body.len(), size);
+        assert!(result.body.bytes().all(|b| b.is_ascii_alphanumeric()));
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_ipv6_local_address() {
+        let port_num = 1925;
+        let server_url = is_valid_url(&format!("http://[::1]:{}/nop/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("OK"))];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let options = ClientOptions {
+            local_address: Some("::1".parse().unwrap()),
+            ..ClientOptions::default()
+        };
+        let client = build_client(&None, true, &options).unwrap();
+        let result = send_request(&client, Method::GET, &server_url, None).unwrap();
+        assert_eq!(result.body, "OK");
+        assert!(measure_phases(&server_url, false, &options).is_ok());
+
+        // An IPv4 local address can't reach an IPv6 literal
+        let options = ClientOptions {
+            local_address: Some("127.0.0.1".parse().unwrap()),
+            ..ClientOptions::default()
+        };
+        let client = build_client(&None, true, &options).unwrap();
+        assert!(send_request(&client, Method::GET, &server_url, None).is_err());
+        assert!(measure_phases(&server_url, false, &options).is_err());
+
+        server.kill();
+        server.wait();
+    }
+
+    /// Starts a server whose handler waits `delay` before answering, sends it one request from
+    /// another thread and kills the server while that request is being answered.
+    fn kill_during_request(
+        port_num: u16,
+        delay: Duration,
+        drain_timeout: Duration,
+    ) -> (Result<HttpResponse, String>, Duration) {
+        let server_url = Url::parse(&format!("http://localhost:{}/slow/", port_num)).unwrap();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        let mut server = Server::new();
+        server.set_drain_timeout(drain_timeout);
+        let handler_started = started.clone();
+        let handlers: Vec<(&Url, Handler)> = vec![(
+            &server_url,
+            Box::new(move |_| {
+                handler_started.fetch_add(1, Ordering::SeqCst);
+                HandlerResponse {
+                    delay,
+                    ..HandlerResponse::ok("slow")
+                }
+            }),
+        )];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let url = server_url.clone();
+        let request = thread::spawn(move || {
+            let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+            send_request(&client, Method::GET, &url, None).map_err(|e| e.to_string())
+        });
+        while started.load(Ordering::SeqCst) == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let shutdown = std::time::Instant::now();
+        server.kill();
+        server.wait();
+        let shutdown = shutdown.elapsed();
+        (request.join().unwrap(), shutdown)
+    }
+
+    #[test]
+    fn test_shutdown_drains_requests() {
+        let (result, _) =
+            kill_during_request(1926, Duration::from_millis(300), Duration::from_secs(5));
+        assert_eq!(result.unwrap().body, "slow");
+    }
+
+    #[test]
+    fn test_shutdown_drain_timeout() {
+        let (result, shutdown) =
+            kill_during_request(1927, Duration::from_secs(10), Duration::from_millis(100));
+        assert!(result.is_err());
+        assert!(
+            shutdown < Duration::from_secs(5),
+            "shutdown took {shutdown:?}"
+        );
+    }
+
+    #[test]
+    fn test_pipelined_batch() {
+        let port_num = 1928;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+        let stats_url = Url::parse(&format!("http://localhost:{}/stats/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        server.set_stats_url(stats_url.clone());
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("OK"))];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let addr = server_url.socket_addrs(|| None).unwrap()[0];
+        let mut pipeliner =
+            Pipeliner::new(&server_url, Target::Tcp(addr), 8, Duration::from_secs(5)).unwrap();
+        pipeliner.send_batch().unwrap();
+        pipeliner.send_batch().unwrap();
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::GET, &stats_url, None).unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&result.body).unwrap();
+        assert_eq!(stats["/nop/"], 16);
+
+        server.kill();
+        server.wait();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let port_num = 1931;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+        let stats_url = Url::parse(&format!("http://localhost:{}/stats/", port_num)).unwrap();
+        let socket = std::env::temp_dir().join("net-bench-uds-test.sock");
+        // A socket left by an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&socket));
+
+        let mut server = Server::new();
+        server.set_stats_url(stats_url.clone());
+        server.set_uds_path(socket.clone());
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("OK"))];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let target = Target::Unix(socket.clone());
+        let mut pipeliner = Pipeliner::new(&server_url, target, 1, Duration::from_secs(5)).unwrap();
+        pipeliner.send_batch().unwrap();
+        pipeliner.send_batch().unwrap();
+
+        // Requests over the socket are counted with those over TCP
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::GET, &stats_url, None).unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&result.body).unwrap();
+        assert_eq!(stats["/nop/"], 2);
+
+        server.kill();
+        server.wait();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_head_request() {
+        let port_num = 1932;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+        let streamed_url = Url::parse(&format!("http://localhost:{}/big/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        let handlers: Vec<(&Url, HandlerFn)> = vec![
+            (&server_url, |_| HandlerResponse::ok("hello")),
+            (&streamed_url, |_| {
+                HandlerResponse::streamed(StreamedBody::new(100_000))
+            }),
+        ];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        for (url, len) in [(&server_url, "5"), (&streamed_url, "100000")] {
+            let get = send_request(&client, Method::GET, url, None).unwrap();
+            assert_eq!(get.headers["content-length"], len);
+            assert_eq!(get.body_len.to_string(), len);
+
+            // The same headers, but no body
+            let head = send_request(&client, Method::HEAD, url, None).unwrap();
+            assert_eq!(head.status, 200);
+            assert_eq!(head.headers["content-length"], len);
+            assert_eq!(head.body_len, 0);
+        }
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_kill_without_worker() {
+        let server = Server::new();
+        server.kill();
+        drop(server);
+
+        let mut server = Server::new();
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_stats_counts_requests() {
+        let port_num = 1922;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+        let stats_url = Url::parse(&format!("http://localhost:{}/stats/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        server.set_stats_url(stats_url.clone());
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("OK"))];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        for _ in 0..3 {
+            send_request(&client, Method::GET, &server_url, None).unwrap();
+        }
+        let result = send_request(&client, Method::GET, &stats_url, None).unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&result.body).unwrap();
+        assert_eq!(stats["/nop/"], 3);
+        assert_eq!(stats["/stats/"], 1);
+
+        server.kill();
+        server.wait();
+    }
+
+    /// Sends many requests with bodies, on several connections at once, so that every receive,
+    /// body read and send goes through its overlapped IO often enough for a leak to show.
+    #[test]
+    fn test_many_requests_release_io() {
+        let port_num = 1930;
+        let server_url = Url::parse(&format!("http://localhost:{}/nop/", port_num)).unwrap();
+        let echo_url = Url::parse(&format!("http://localhost:{}/echo/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        server.set_workers(4);
+        server.set_echo_url(echo_url.clone());
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse::ok("OK"))];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        thread::scope(|scope| {
+            for worker in 0..4 {
+                let (server_url, echo_url) = (&server_url, &echo_url);
+                scope.spawn(move || {
+                    let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+                    for i in 0..500 {
+                        let body = vec![b'a' + (worker + i % 26) as u8; 1 + i * 37 % 100_000];
+                        let result =
+                            send_request(&client, Method::POST, echo_url, Some(&body)).unwrap();
+                        assert_eq!(result.body.len(), body.len());
+                        let result = send_request(&client, Method::GET, server_url, None).unwrap();
+                        assert_eq!(result.body, "OK");
+                    }
+                });
+            }
+        });
+
+        // Other tests' servers hold a few outstanding receives, but a leak would hold
+        // thousands of objects by now
+        #[cfg(windows)]
+        {
+            let live = crate::httpsys::LIVE_OVERLAPPED.load(Ordering::SeqCst);
+            assert!(live < 100, "{live} overlapped objects still live");
+        }
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_capturing_handler() {
+        let port_num = 1920;
+        let server_url = Url::parse(&format!("http://localhost:{}/count/", port_num)).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let mut server = Server::new();
+        let handler_count = count.clone();
+        let handlers: Vec<(&Url, Handler)> = vec![(
+            &server_url,
+            Box::new(move |_| {
+                let n = handler_count.fetch_add(1, Ordering::SeqCst) + 1;
+                HandlerResponse::ok(&n.to_string())
+            }),
+        )];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        for expected in 1..=3 {
+            let result = send_request(&client, Method::GET, &server_url, None).unwrap();
+            assert_eq!(result.body, expected.to_string());
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_handler_status_and_content_type() {
+        let port_num = 1921;
+        let server_url = Url::parse(&format!("http://localhost:{}/busy/", port_num)).unwrap();
+
+        let mut server = Server::new();
+        let handlers: Vec<(&Url, HandlerFn)> = vec![(&server_url, |_| HandlerResponse {
+            status: 503,
+            content_type: "text/plain".to_string(),
+            ..HandlerResponse::ok("busy")
+        })];
+
+        server.define_handlers(handlers);
+        wait_until_listening(&server_url);
+
+        let client = build_client(&None, true, &ClientOptions::default()).unwrap();
+        let result = send_request(&client, Method::GET, &server_url, None).unwrap();
+        assert_eq!(result.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(result.headers["content-type"], "text/plain");
+        assert_eq!(result.body, "busy");
+
+        server.kill();
+        server.wait();
+    }
+
+    #[test]
+    fn test_content_type_for() {
+        use crate::server::content_type_for;
+        use std::path::Path;
+
+        assert_eq!(content_type_for(Path::new("index.HTML")), "text/html");
+        assert_eq!(content_type_for(Path::new("data.json")), "application/json");
+        assert_eq!(content_type_for(Path::new("photo.jpeg")), "image/jpeg");
+        assert_eq!(
+            content_type_for(Path::new("blob")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("64").unwrap(), 64);
+        assert_eq!(parse_size("64b").unwrap(), 64);
+        assert_eq!(parse_size("64kb").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("1MB").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size(&format_size(1536)).unwrap(), 1536);
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("5").unwrap(), Duration::from_secs(5));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("1e30s").is_err());
+    }
+
+    #[test]
+    fn test_measure_config_defaults() {
+        let args = Args::parse_from(["test-httpsys", "client", "http://localhost/"]);
+        let Mode::Client { measure, .. } = args.command else {
+            panic!("expected client mode");
+        };
+        let default = MeasureConfig::default();
+        assert_eq!(measure.warmup, default.warmup);
+        assert_eq!(measure.min_iterations, default.min_iterations);
+        assert_eq!(measure.max_iterations, default.max_iterations);
+        assert_eq!(measure.stable_threshold, default.stable_threshold);
+        assert_eq!(measure.outlier_threshold, default.outlier_threshold);
+    }
+
+    #[test]
+    fn test_warmup_excluded_from_stats() {
+        let config = MeasureConfig {
+            warmup: 3,
+            ..MeasureConfig::default()
+        };
+        let calls = AtomicUsize::new(0);
+        let result = measure_latency_concurrent(&config, 1, || {
+            if calls.fetch_add(1, Ordering::Relaxed) < 3 {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        assert_eq!(result.warmup.len(), 3);
+        assert!(result
+            .warmup
+            .iter()
+            .all(|d| *d >= Duration::from_millis(20)));
+        assert!(result.max < Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_failures_excluded_from_stats() {
+        let config = MeasureConfig {
+            warmup: 0,
+            ..MeasureConfig::default()
+        };
+        let calls = AtomicUsize::new(0);
+        let result = measure_latency_concurrent(&config, 1, || {
+            if calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(4) {
+                thread::sleep(Duration::from_millis(20));
+                Err("timed out")
+            } else {
+                Ok(())
+            }
+        });
+
+        assert!(result.failures > 0);
+        // Outlier rejection may drop more samples, but never counts them as failures
+        assert!(result.samples.len() + result.failures <= result.iterations);
+        assert!(result.max < Duration::from_millis(20));
+        assert!(result.failure_rate() > 0.0 && result.failure_rate() < 1.0);
+    }
+
+    #[test]
+    fn test_count_measures_exact_requests() {
+        let config = MeasureConfig {
+            warmup: 2,
+            ..MeasureConfig::default()
+        };
+        let calls = AtomicUsize::new(0);
+        let result = measure_latency_count(&config, 25, 3, || {
+            if calls.fetch_add(1, Ordering::Relaxed) % 5 == 4 {
+                Err("refused")
+            } else {
+                Ok(())
+            }
+        });
+
+        // Every worker warms up, and the measured calls are shared between them
+        assert_eq!(calls.load(Ordering::Relaxed), 25 + 3 * 2);
+        assert_eq!(result.iterations, 25);
+        // Every sample is kept, with no outlier rejection
+        assert_eq!(result.samples.len() + result.failures, 25);
+        // The workers' calls are interleaved in the order they started
+        assert_eq!(result.timeline.len(), result.samples.len());
+        assert_eq!(result.timeline[0].at, Duration::ZERO);
+        assert!(result
+            .timeline
+            .windows(2)
+            .all(|pair| pair[0].at <= pair[1].at));
+    }
+
+    #[test]
+    fn test_fail_fast_stops_at_first_failure() {
+        let config = MeasureConfig {
+            warmup: 2,
+            fail_fast: true,
+            ..MeasureConfig::default()
+        };
+        let calls = AtomicUsize::new(0);
+        let first_failure = util::FirstFailure::default();
+        let result = measure_latency_concurrent(&config, 1, || {
+            let result = if calls.fetch_add(1, Ordering::Relaxed) == 6 {
+                Err("refused")
+            } else {
+                Ok(())
+            };
+            first_failure.record(&result);
+            result
+        });
+
+        // The seventh call is the fifth measured one, and nothing is sent after it
+        assert_eq!(calls.load(Ordering::Relaxed), 7);
+        assert_eq!((result.iterations, result.failures), (5, 1));
+        assert!(first_failure.report(config.warmup));
+
+        let count = measure_latency_count(&config, 50, 4, || Err::<(), _>("refused"));
+        assert!(count.iterations <= 4, "{}", count.iterations);
+        assert!(!util::FirstFailure::default().report(0));
+    }
+
+    #[test]
+    fn test_first_failure_keeps_earliest_call() {
+        let failure = util::FirstFailure::default();
+        failure.record(&Ok::<(), &str>(()));
+        failure.record(&Err::<(), _>("first"));
+        failure.record(&Err::<(), _>("second"));
+        assert_eq!(
+            failure.describe(0).as_deref(),
+            Some("Request 2 failed: first")
+        );
+        assert_eq!(
+            failure.describe(2).as_deref(),
+            Some("Warmup request 2 failed: first")
+        );
+
+        // Calls finishing out of order can't be numbered
+        let failure = util::FirstFailure::new(4);
+        failure.record(&Err::<(), _>("refused"));
+        assert_eq!(
+            failure.describe(0).as_deref(),
+            Some("A request failed: refused")
+        );
+    }
+
+    #[test]
+    fn test_stops_once_tail_latency_settles() {
+        let config = MeasureConfig {
+            warmup: 0,
+            max_iterations: 40,
+            stable_threshold: 0.2,
+            ..MeasureConfig::default()
+        };
+        let result = measure_latency_concurrent(&config, 1, || {
+            thread::sleep(Duration::from_millis(2));
+        });
+        assert!(result.iterations < config.max_iterations);
+
+        // Latency that keeps rising never settles, though every sample stays near the mean
+        let calls = AtomicUsize::new(0);
+        let result = measure_latency_concurrent(&config, 1, || {
+            let call = calls.fetch_add(1, Ordering::Relaxed) as u64;
+            thread::sleep(Duration::from_micros(500 * call));
+        });
+        assert_eq!(result.iterations, config.max_iterations);
+    }
+
+    #[test]
+    fn test_rate_reports_queueing_apart_from_latency() {
+        let config = MeasureConfig {
+            warmup: 2,
+            max_iterations: 10,
+            ..MeasureConfig::default()
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        // 100 per second with one request at a time that takes 30ms can't keep up
+        let result = runtime.block_on(measure_latency_at_rate(&config, 100.0, None, 1, || async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }));
+
+        assert_eq!(result.service.iterations, 10);
+        assert_eq!(result.service.warmup.len(), 2);
+        assert!(result.service.latency >= Duration::from_millis(30));
+        assert!(result.service.latency < Duration::from_millis(100));
+        // Each request falls a further 20ms behind its due time
+        assert!(result.queueing.max >= Duration::from_millis(150));
+        assert!(result.queueing.max > result.queueing.min);
+    }
+
+    #[test]
+    fn test_rate_leaves_warmups_out_of_requests_per_second() {
+        let config = MeasureConfig {
+            warmup: 5,
+            max_iterations: 5,
+            ..MeasureConfig::default()
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        // Slow warmups would drag the rate down to under 100 per second were they counted
+        let calls = AtomicUsize::new(0);
+        let result = runtime.block_on(measure_latency_at_rate(&config, 1000.0, None, 1, || {
+            let call = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                let wait = if call < 5 { 20 } else { 1 };
+                tokio::time::sleep(Duration::from_millis(wait)).await;
+            }
+        }));
+
+        assert_eq!(result.service.iterations, 5);
+        assert!(result.service.requests_per_second > 200.0);
+    }
+
+    #[test]
+    fn test_is_warmup_marks_each_workers_warmups() {
+        let config = MeasureConfig {
+            warmup: 3,
+            ..MeasureConfig::default()
+        };
+        let warmups = AtomicUsize::new(0);
+        let measured = AtomicUsize::new(0);
+        util::measure_latency_count(&config, 4, 2, || {
+            let counter = if util::is_warmup() {
+                &warmups
+            } else {
+                &measured
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!((warmups.into_inner(), measured.into_inner()), (6, 4));
+
+        // At a fixed rate the calls overlap on one thread, so each keeps its own answer
+        let config = MeasureConfig {
+            warmup: 2,
+            max_iterations: 3,
+            ..MeasureConfig::default()
+        };
+        let flags = Mutex::new(Vec::new());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(measure_latency_at_rate(
+            &config,
+            1000.0,
+            None,
+            5,
+            || async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                flags.lock().unwrap().push(util::is_warmup());
+            },
+        ));
+        let flags = flags.into_inner().unwrap();
+        assert_eq!(flags.iter().filter(|warmup| **warmup).count(), 2);
+        assert_eq!(flags.len(), 5);
+        assert!(!util::is_warmup());
+    }
+
+    #[test]
+    fn test_warm_connections_overlap() {
+        let in_flight = AtomicUsize::new(0);
+        let most_in_flight = AtomicUsize::new(0);
+        let calls = AtomicUsize::new(0);
+        util::warm_connections(4, &|| {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            most_in_flight.fetch_max(now, Ordering::SeqCst);
+            calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(calls.into_inner(), 4);
+        assert_eq!(most_in_flight.into_inner(), 4);
+    }
+
+    #[test]
+    fn test_confidence_interval_brackets_mean() {
+        let config = MeasureConfig {
+            warmup: 0,
+            ..MeasureConfig::default()
+        };
+        let calls = AtomicUsize::new(0);
+        let result = measure_latency_concurrent(&config, 1, || {
+            let delay = if calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(2) {
+                1
+            } else {
+                3
+            };
+            thread::sleep(Duration::from_millis(delay));
+        });
+
+        assert!(result.ci_low <= result.latency);
+        assert!(result.latency <= result.ci_high);
+        assert!(result.ci_high > result.ci_low);
+    }
+
+    #[test]
+    fn test_combine_runs_pools_samples() {
+        let config = MeasureConfig {
+            warmup: 1,
+            ..MeasureConfig::default()
+        };
+        let runs: Vec<_> = (0..3)
+            .map(|_| measure_latency_concurrent(&config, 1, || ()))
+            .collect();
+        let combined = combine_runs(&runs);
+
+        let samples: usize = runs.iter().map(|r| r.samples.len()).sum();
+        assert_eq!(combined.samples.len(), samples);
+        assert_eq!(
+            combined.iterations,
+            runs.iter().map(|r| r.iterations).sum::<usize>()
+        );
+        assert_eq!(combined.warmup.len(), 3);
+        assert!(combined.min <= combined.latency && combined.latency <= combined.max);
+        // Each run's calls follow the previous run's
+        let calls: usize = runs.iter().map(|r| r.timeline.len()).sum();
+        assert_eq!(combined.timeline.len(), calls);
+        assert!(combined
+            .timeline
+            .windows(2)
+            .all(|pair| pair[0].at <= pair[1].at));
+    }
+
+    #[test]
+    fn test_histogram_counts_every_sample() {
+        let samples: Vec<_> = [1, 1, 2, 9, 10, 10]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+        let counts = util::histogram(&samples, 10);
+        assert_eq!(counts.len(), 10);
+        assert_eq!(counts.iter().sum::<usize>(), samples.len());
+        assert_eq!(counts[0], 2);
+        assert_eq!(counts[1], 1);
+        assert_eq!(counts[9], 2);
+
+        let equal = vec![Duration::from_millis(5); 4];
+        assert_eq!(util::histogram(&equal, 10)[0], 4);
+        assert_eq!(util::histogram(&[], 10).iter().sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn test_seeded_payload_is_repeatable() {
+        for pattern in PayloadPattern::value_variants() {
+            let first = generate_payload(&mut StdRng::seed_from_u64(42), *pattern, 256);
+            let second = generate_payload(&mut StdRng::seed_from_u64(42), *pattern, 256);
+            assert_eq!(first.len(), 256);
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn test_slo_violations() {
+        let ms = Duration::from_millis;
+        let samples: Vec<Duration> = (1..=100).map(ms).collect();
+        let latency = util::summarize_samples(&samples, 0, Duration::ZERO);
+        let (p99, mean) = (latency.p99, latency.latency);
+        let single = [("Request", latency)];
+
+        assert!(slo_violations(&single, None, None).is_empty());
+        assert!(slo_violations(&single, Some(p99), Some(mean)).is_empty());
+        let violations = slo_violations(&single, Some(ms(50)), Some(ms(10)));
+        assert_eq!(
+            violations,
+            [
+                format!("p99 latency {p99:?} is over 50ms"),
+                format!("mean latency {mean:?} is over 10ms"),
+            ]
+        );
+
+        // Only the measurement over the SLO is reported, by name
+        let fast = util::summarize_samples(&[ms(1), ms(2)], 0, Duration::ZERO);
+        let [(_, slow)] = single;
+        let both = [("fast", fast), ("slow", slow)];
+        let violations = slo_violations(&both, Some(ms(50)), None);
+        assert_eq!(violations.len(), 1);
+        assert!(
+            violations[0].starts_with("slow p99 latency "),
+            "{}",
+            violations[0]
+        );
+    }
+
+    #[test]
+    fn test_jittered_delay() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let (mean, jitter) = (Duration::from_millis(10), Duration::from_millis(4));
+
+        let uniform: Vec<Duration> = (0..1000)
+            .map(|_| jittered_delay(&mut rng, JitterDistribution::Uniform, mean, jitter))
+            .collect();
+        assert!(uniform
+            .iter()
+            .all(|d| (mean - jitter..=mean + jitter).contains(d)));
+        assert!(uniform.iter().any(|d| *d < Duration::from_millis(7)));
+        assert!(uniform.iter().any(|d| *d > Duration::from_millis(13)));
+
+        let normal: Vec<f64> = (0..10_000)
+            .map(|_| jittered_delay(&mut rng, JitterDistribution::Normal, mean, jitter))
+            .map(|d| d.as_secs_f64() * 1000.0)
+            .collect();
+        let average = normal.iter().sum::<f64>() / normal.len() as f64;
+        let variance =
+            normal.iter().map(|d| (d - average).powi(2)).sum::<f64>() / normal.len() as f64;
+        assert!((average - 10.0).abs() < 0.2, "{average}");
+        assert!((variance.sqrt() - 4.0).abs() < 0.2, "{}", variance.sqrt());
+
+        // Drawn below zero, the response goes at once
+        let clamped = (0..100).map(|_| {
+            jittered_delay(
+                &mut rng,
+                JitterDistribution::Uniform,
+                Duration::ZERO,
+                jitter,
+            )
+        });
+        assert!(clamped.into_iter().any(|d| d.is_zero()));
+    }
+
+    #[test]
+    fn test_payload_patterns() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let random = generate_payload(&mut rng, PayloadPattern::Random, 1000);
+        assert!(random.iter().all(u8::is_ascii_alphanumeric));
+        assert!(generate_payload(&mut rng, PayloadPattern::Zeros, 1000)
+            .iter()
+            .all(|b| *b == 0));
+
+        let text = generate_payload(&mut rng, PayloadPattern::Text, 1000);
+        let text = String::from_utf8(text).unwrap();
+        assert!(text
+            .split_whitespace()
+            .all(|word| LOREM_IPSUM.iter().any(|w| w.starts_with(word))));
+
+        // Only the binary pattern reaches bytes outside ASCII
+        let binary = generate_payload(&mut rng, PayloadPattern::Binary, 1000);
+        assert!(binary.iter().any(|b| *b >= 0x80));
+    }
+
+    #[test]
+    fn test_payload_sizes() {
+        assert_eq!(payload_sizes(1000, 2000, 1.25), [1000, 1250, 1562, 1952]);
+        // Small sizes still grow by a byte a step
+        assert_eq!(payload_sizes(1, 4, 1.1), [1, 2, 3, 4]);
+        assert_eq!(payload_sizes(64, 64, 2.0), [64]);
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let policy = util::RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(1),
+        };
+
+        let attempts = AtomicUsize::new(0);
+        let retried = with_retries(&policy, || {
+            if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                Err("fail")
+            } else {
+                Ok(())
+            }
+        });
+        assert!(retried.result.is_ok());
+        assert_eq!(retried.retries, 2);
+
+        let retried = with_retries(&policy, || Err::<(), _>("fail"));
+        assert!(retried.result.is_err());
+        assert_eq!(retried.retries, 3);
+    }
+
+    #[test]
+    fn test_timestamped_outputs() {
+        let time = chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let dir = Path::new("runs");
+
+        let (plot, csv, json) = timestamped_outputs(dir, "request-latency.svg", time);
+        assert_eq!(plot, dir.join("request-latency-20240101-120000.svg"));
+        assert_eq!(csv, dir.join("request-latency-20240101-120000.csv"));
+        assert_eq!(json, dir.join("request-latency-20240101-120000.json"));
+
+        // The directory the plot was named in is replaced, and the extension kept
+        let (plot, _, _) = timestamped_outputs(dir, "plots/echo.png", time);
+        assert_eq!(plot, dir.join("echo-20240101-120000.png"));
+    }
 }
@@ -0,0 +1,295 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+use reqwest::Url;
+
+/// Where the requests are sent.
+pub enum Target {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, which leaves the TCP loopback out of a local measurement.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An open connection to the target.
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn open(target: &Target, timeout: Duration) -> io::Result<Self> {
+        match target {
+            Target::Tcp(addr) => {
+                let stream = TcpStream::connect_timeout(addr, timeout)?;
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(timeout))?;
+                Ok(Connection::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(timeout))?;
+                Ok(Connection::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Sends HTTP/1.1 requests in batches over one connection, writing a whole batch before reading
+/// any response, which reqwest can't do as it waits for each response before the next request.
+pub struct Pipeliner {
+    target: Target,
+    timeout: Duration,
+    request: Vec<u8>,
+    depth: usize,
+    /// Opened on the first batch, and again after a batch fails, as the responses left on a
+    /// failed connection can't be matched to their requests.
+    stream: Option<BufReader<Connection>>,
+}
+
+impl Pipeliner {
+    /// Prepares `depth` GETs of `url` per batch, sent to `target`. A batch whose responses
+    /// don't all arrive within `timeout` fails. Only plain `http` URLs can be pipelined this way.
+    pub fn new(url: &Url, target: Target, depth: usize, timeout: Duration) -> io::Result<Self> {
+        if url.scheme() != "http" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't pipeline {} URLs, only http", url.scheme()),
+            ));
+        }
+        let host = url.host_str().unwrap_or("localhost");
+        let host = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        let path = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        };
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nAccept: */*\r\n\r\n");
+
+        Ok(Pipeliner {
+            target,
+            timeout,
+            request: request.repeat(depth.max(1)).into_bytes(),
+            depth: depth.max(1),
+            stream: None,
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Writes every request of a batch in one go, then reads all of their responses.
+    pub fn send_batch(&mut self) -> io::Result<()> {
+        let result = self.try_send_batch();
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn try_send_batch(&mut self) -> io::Result<()> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                let stream = Connection::open(&self.target, self.timeout)?;
+                self.stream.insert(BufReader::new(stream))
+            }
+        };
+
+        stream.get_mut().write_all(&self.request)?;
+        for _ in 0..self.depth {
+            read_response(stream)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads one response off the connection, discarding its body, and fails on an error status.
+/// The body must be framed by a length or chunked encoding, as a body running to the end of
+/// the connection would swallow the responses after it.
+fn read_response(reader: &mut impl BufRead) -> io::Result<()> {
+    let status_line = read_line(reader)?;
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid(format!("bad status line: {status_line:?}")))?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid(format!("bad header: {line:?}")));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            let len = value
+                .parse()
+                .map_err(|_| invalid(format!("bad length: {value:?}")))?;
+            content_length = Some(len);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.to_ascii_lowercase().contains("chunked");
+        }
+    }
+
+    // 1xx, 204 and 304 responses never have a body
+    let bodyless = status < 200 || status == 204 || status == 304;
+    if chunked {
+        read_chunked(reader)?;
+    } else if let Some(len) = content_length {
+        skip(reader, len)?;
+    } else if !bodyless {
+        return Err(invalid("response has no length, so can't be pipelined"));
+    }
+
+    if status >= 400 {
+        return Err(io::Error::other(format!("server returned status {status}")));
+    }
+    Ok(())
+}
+
+/// Discards a chunked body, including any trailers.
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<()> {
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid(format!("bad chunk size: {line:?}")))?;
+        if size == 0 {
+            while !read_line(reader)?.is_empty() {}
+            return Ok(());
+        }
+        skip(reader, size)?;
+        read_line(reader)?;
+    }
+}
+
+fn skip(reader: &mut impl BufRead, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// One CRLF terminated line without its terminator. The connection closing first is an error,
+/// as every response is expected in full.
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_read_response_framing() {
+        let responses = concat!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 9\r\n\r\nNot Found",
+            "HTTP/1.1 200 OK\r\n\r\n",
+        );
+        let mut reader = BufReader::new(responses.as_bytes());
+        read_response(&mut reader).unwrap();
+        read_response(&mut reader).unwrap();
+        read_response(&mut reader).unwrap();
+        assert!(read_response(&mut reader).is_err());
+        // A body with no length can't be told apart from the next response
+        assert!(read_response(&mut reader).is_err());
+        assert!(read_response(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_batch_is_written_before_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Answers only once all three requests have arrived
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while received.windows(4).filter(|w| w == b"\r\n\r\n").count() < 3 {
+                let len = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..len]);
+            }
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK".repeat(3);
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let url = Url::parse(&format!("http://{addr}/test/?q=1")).unwrap();
+        let mut pipeliner =
+            Pipeliner::new(&url, Target::Tcp(addr), 3, Duration::from_secs(5)).unwrap();
+        pipeliner.send_batch().unwrap();
+
+        let received = server.join().unwrap();
+        assert!(received.starts_with(&format!("GET /test/?q=1 HTTP/1.1\r\nHost: {addr}\r\n")));
+
+        let https = Url::parse("https://localhost/").unwrap();
+        assert!(Pipeliner::new(&https, Target::Tcp(addr), 3, Duration::from_secs(5)).is_err());
+    }
+}
@@ -0,0 +1,921 @@
+use clap::ValueEnum;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+use plotters::style::{BLUE, WHITE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::util::{format_size, format_throughput, percentile, throughput_mb_per_sec};
+
+const FONT: &str = "Fira Code";
+/// Plot size in pixels when none is given.
+pub const PLOT_WIDTH: u32 = 800;
+pub const PLOT_HEIGHT: u32 = 400;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub name: String,
+    pub latency: u64,
+    pub payload_size: u64,
+    /// Fastest and slowest latency measured at this size, drawn as an error bar. Missing from
+    /// files saved before they were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_latency: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency: Option<u64>,
+    /// Average time until the first byte of the response arrived, apart from the rest of the
+    /// download. Only recorded by the test command, and drawn as a series of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte: Option<u64>,
+}
+
+/// Saves measurements as JSON so they can be re-plotted later without re-running the test.
+pub fn save_measurements(path: &Path, records: &[Measurement]) -> Result<(), Box<dyn Error>> {
+    let mut out = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut out, records)?;
+    out.flush()?;
+    Ok(())
+}
+
+pub fn load_measurements(path: &Path) -> Result<Vec<Measurement>, Box<dyn Error>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Which value of each measurement is drawn on the Y axis.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlotMetric {
+    /// Average latency per request.
+    #[default]
+    Latency,
+    /// Payload bytes moved per second.
+    Throughput,
+}
+
+impl PlotMetric {
+    pub fn y_label(self) -> &'static str {
+        match self {
+            PlotMetric::Latency => "Average latency",
+            PlotMetric::Throughput => "MB/s",
+        }
+    }
+
+    /// Latency in nanoseconds or throughput in MB/s.
+    fn value(self, record: &Measurement) -> f64 {
+        match self {
+            PlotMetric::Latency => record.latency as f64,
+            PlotMetric::Throughput => {
+                throughput_mb_per_sec(record.payload_size, Duration::from_nanos(record.latency))
+            }
+        }
+    }
+
+    /// The lowest and highest values at this point, if the spread was recorded. Throughput is
+    /// highest at the fastest latency, so the bounds are ordered after converting.
+    fn spread(self, record: &Measurement) -> Option<(f64, f64)> {
+        let (min, max) = (record.min_latency?, record.max_latency?);
+        let value = |latency| {
+            self.value(&Measurement {
+                latency,
+                ..record.clone()
+            })
+        };
+        let (a, b) = (value(min), value(max));
+        Some((a.min(b), a.max(b)))
+    }
+
+    /// The axis description, naming the latency unit picked for the largest value on the axis.
+    fn y_desc(self, y_label: &str, y_max: f64) -> String {
+        match self {
+            PlotMetric::Latency => format!("{} ({})", y_label, TimeUnit::for_nanos(y_max).suffix()),
+            PlotMetric::Throughput => y_label.to_string(),
+        }
+    }
+
+    fn format_label(self, value: f64, y_max: f64) -> String {
+        match self {
+            PlotMetric::Latency => {
+                let unit = TimeUnit::for_nanos(y_max);
+                format!("{:.1} {}", value / unit.nanos(), unit.suffix())
+            }
+            PlotMetric::Throughput => format_throughput(value),
+        }
+    }
+}
+
+/// Unit latency labels are shown in, so that same-machine latencies well under a millisecond
+/// don't all read `0.0 ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeUnit {
+    Nanos,
+    Micros,
+    Millis,
+    Secs,
+}
+
+impl TimeUnit {
+    /// The largest unit in which `nanos` is at least 1.
+    fn for_nanos(nanos: f64) -> Self {
+        if nanos >= 1e9 {
+            TimeUnit::Secs
+        } else if nanos >= 1e6 {
+            TimeUnit::Millis
+        } else if nanos >= 1e3 {
+            TimeUnit::Micros
+        } else {
+            TimeUnit::Nanos
+        }
+    }
+
+    fn nanos(self) -> f64 {
+        match self {
+            TimeUnit::Nanos => 1.0,
+            TimeUnit::Micros => 1e3,
+            TimeUnit::Millis => 1e6,
+            TimeUnit::Secs => 1e9,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            TimeUnit::Nanos => "ns",
+            TimeUnit::Micros => "µs",
+            TimeUnit::Millis => "ms",
+            TimeUnit::Secs => "s",
+        }
+    }
+}
+
+/// How measurements are drawn.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlotKind {
+    /// One line per name, across payload sizes.
+    #[default]
+    Line,
+    /// One bar per name, averaged over its payload sizes, for comparing endpoints.
+    Bar,
+}
+
+/// Colour for the series at `index`. The first series keeps the original blue so single-run
+/// plots look the same; later ones are picked from a palette so overlaid runs are distinct.
+fn series_color(index: usize) -> RGBAColor {
+    if index == 0 {
+        BLUE.to_rgba()
+    } else {
+        Palette99::pick(index - 1).to_rgba()
+    }
+}
+
+/// Plots each group of measurements sharing a `name` as its own line or bar, so several runs
+/// can be compared by giving their records different names. `resolution` is the width and
+/// height in pixels, or SVG units.
+pub fn write_plot(
+    records: &[Measurement],
+    caption: &str,
+    y_label: &str,
+    path: &str,
+    metric: PlotMetric,
+    kind: PlotKind,
+    resolution: (u32, u32),
+) -> Result<(), Box<dyn Error>> {
+    if records.is_empty() {
+        return Err("no measurements to plot".into());
+    }
+    let records = &with_first_byte_series(records, metric);
+
+    if is_png(path) {
+        let root = BitMapBackend::new(path, resolution).into_drawing_area();
+        draw(root, records, caption, y_label, metric, kind)
+    } else {
+        let root = SVGBackend::new(path, resolution).into_drawing_area();
+        draw(root, records, caption, y_label, metric, kind)
+    }
+}
+
+/// The records along with, when latency is plotted, a `<name> (first byte)` series from the
+/// records that have a time to first byte, so the gap to the full latency shows how much of
+/// it is spent downloading the body.
+fn with_first_byte_series(records: &[Measurement], metric: PlotMetric) -> Vec<Measurement> {
+    let mut all = records.to_vec();
+    if metric == PlotMetric::Latency {
+        all.extend(records.iter().filter_map(|record| {
+            record.first_byte.map(|first_byte| Measurement {
+                name: format!("{} (first byte)", record.name),
+                latency: first_byte,
+                payload_size: record.payload_size,
+                min_latency: None,
+                max_latency: None,
+                first_byte: None,
+            })
+        }));
+    }
+    all
+}
+
+/// Plots the latency of the baseline and current runs of a comparison as overlaid lines, with
+/// a red dot on each point of `regressed`.
+pub fn write_comparison_plot(
+    records: &[Measurement],
+    regressed: &[&Measurement],
+    caption: &str,
+    path: &str,
+    resolution: (u32, u32),
+) -> Result<(), Box<dyn Error>> {
+    if records.is_empty() {
+        return Err("no measurements to plot".into());
+    }
+    let metric = PlotMetric::Latency;
+
+    if is_png(path) {
+        let root = BitMapBackend::new(path, resolution).into_drawing_area();
+        draw_plot(root, records, caption, metric.y_label(), metric, regressed)
+    } else {
+        let root = SVGBackend::new(path, resolution).into_drawing_area();
+        draw_plot(root, records, caption, metric.y_label(), metric, regressed)
+    }
+}
+
+/// Calls the trend line of `write_timeseries_plot` averages over by default.
+pub const TREND_WINDOW: usize = 20;
+
+/// The mean of each value and up to `window - 1` values before it, so the line starts at the
+/// first value rather than after a full window.
+fn moving_average(values: &[f64], window: usize) -> Vec<f64> {
+    let window = window.max(1);
+    let mut sum = 0.0;
+    values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            sum += value;
+            if index >= window {
+                sum -= values[index - window];
+            }
+            sum / (index + 1).min(window) as f64
+        })
+        .collect()
+}
+
+/// Percentiles `write_timeseries_plot` can draw a reference line at.
+const TREND_PERCENTILES: [f64; 3] = [50.0, 95.0, 99.0];
+
+/// Plots the latency of each call of every series against its index in the run, as a faint
+/// point per call with a moving average over `window` calls drawn through them, so drift and
+/// spikes during a run stand out. With `percentiles`, a dashed line labelled at the right edge
+/// marks each series' p50, p95 and p99 across the whole run.
+pub fn write_timeseries_plot(
+    series: &[(&str, Vec<Duration>)],
+    window: usize,
+    percentiles: bool,
+    caption: &str,
+    path: &str,
+    resolution: (u32, u32),
+) -> Result<(), Box<dyn Error>> {
+    if series.iter().all(|(_, latencies)| latencies.is_empty()) {
+        return Err("no measurements to plot".into());
+    }
+
+    if is_png(path) {
+        let root = BitMapBackend::new(path, resolution).into_drawing_area();
+        draw_timeseries_plot(root, series, window, percentiles, caption)
+    } else {
+        let root = SVGBackend::new(path, resolution).into_drawing_area();
+        draw_timeseries_plot(root, series, window, percentiles, caption)
+    }
+}
+
+/// A `.png` extension writes a bitmap, anything else writes SVG.
+fn is_png(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+fn draw<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    records: &[Measurement],
+    caption: &str,
+    y_label: &str,
+    metric: PlotMetric,
+    kind: PlotKind,
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    match kind {
+        PlotKind::Line => draw_plot(root, records, caption, y_label, metric, &[]),
+        PlotKind::Bar => draw_bar_plot(root, records, caption, y_label, metric),
+    }
+}
+
+fn group_by_name(records: &[Measurement]) -> BTreeMap<&str, Vec<&Measurement>> {
+    let mut groups: BTreeMap<&str, Vec<&Measurement>> = BTreeMap::new();
+
+    for record in records.iter() {
+        let group = groups.entry(record.name.as_str()).or_default();
+        group.push(record);
+    }
+
+    groups
+}
+
+fn draw_plot<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    records: &[Measurement],
+    caption: &str,
+    y_label: &str,
+    metric: PlotMetric,
+    highlights: &[&Measurement],
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    let groups = group_by_name(records);
+
+    root.fill(&WHITE)?;
+
+    // The range covers the error bars as well as the averages
+    let bounds: Vec<(f64, f64)> = records
+        .iter()
+        .map(|m| {
+            metric
+                .spread(m)
+                .unwrap_or((metric.value(m), metric.value(m)))
+        })
+        .collect();
+    let y_min = bounds.iter().map(|b| b.0).fold(f64::INFINITY, f64::min);
+    let y_max = bounds.iter().map(|b| b.1).fold(f64::NEG_INFINITY, f64::max);
+    let y_diff = y_max - y_min;
+    // When every value is the same the range would collapse, so pad by 10% of the value,
+    // or 1 unit if the value is zero, to keep the line in the middle of the chart
+    let y_padding = if y_diff == 0.0 {
+        (y_min * 0.1).max(1.0)
+    } else {
+        (y_diff / 10.0).min(y_min)
+    };
+    let y_start = (y_min - y_padding).max(0.0);
+    let y_end = y_max + y_padding;
+
+    // Log axes can't start at zero, and need a non-empty range when there is a single size
+    let x_min = records.iter().map(|m| m.payload_size).min().unwrap().max(1);
+    let x_max = records.iter().map(|m| m.payload_size).max().unwrap().max(1);
+    let (x_min, x_max) = if x_min == x_max {
+        ((x_min / 2).max(1), x_max * 2)
+    } else {
+        (x_min, x_max)
+    };
+
+    let mut chart = ChartBuilder::on(&root)
+        .margin(10)
+        .caption(caption, (FONT, 20))
+        .set_label_area_size(LabelAreaPosition::Left, 70)
+        .set_label_area_size(LabelAreaPosition::Right, 70)
+        .set_label_area_size(LabelAreaPosition::Bottom, 40)
+        .build_cartesian_2d((x_min..x_max).log_scale(), y_start..y_end)?;
+
+    chart
+        .configure_mesh()
+        .disable_y_mesh()
+        .x_label_formatter(&|v| format_size(*v))
+        .y_label_formatter(&|v| metric.format_label(*v, y_end))
+        .x_labels(20)
+        .y_labels(20)
+        .y_desc(metric.y_desc(y_label, y_end))
+        .x_desc("Size")
+        .draw()?;
+
+    for (index, records) in groups.values().enumerate() {
+        let color = series_color(index);
+        chart
+            .draw_series(LineSeries::new(
+                records
+                    .iter()
+                    .map(|record| (record.payload_size, metric.value(record))),
+                color,
+            ))?
+            .label(records[0].name.as_str())
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+
+        // Only points whose spread was recorded get an error bar
+        chart.draw_series(records.iter().filter_map(|record| {
+            let (low, high) = metric.spread(record)?;
+            Some(ErrorBar::new_vertical(
+                record.payload_size,
+                low,
+                metric.value(record),
+                high,
+                color,
+                6,
+            ))
+        }))?;
+    }
+
+    chart.draw_series(
+        highlights.iter().map(|record| {
+            Circle::new((record.payload_size, metric.value(record)), 5, RED.filled())
+        }),
+    )?;
+
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::UpperLeft)
+        .label_font((FONT, 13))
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+
+    root.present()?;
+
+    Ok(())
+}
+
+fn draw_bar_plot<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    records: &[Measurement],
+    caption: &str,
+    y_label: &str,
+    metric: PlotMetric,
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    let groups = group_by_name(records);
+    let names: Vec<&str> = groups.keys().copied().collect();
+    let values: Vec<f64> = groups
+        .values()
+        .map(|group| group.iter().map(|m| metric.value(m)).sum::<f64>() / group.len() as f64)
+        .collect();
+
+    root.fill(&WHITE)?;
+
+    // Bars start at zero, so only the top needs headroom
+    let y_max = values.iter().copied().fold(0.0, f64::max);
+    let y_max = if y_max > 0.0 { y_max * 1.1 } else { 1.0 };
+
+    let mut chart = ChartBuilder::on(&root)
+        .margin(10)
+        .caption(caption, (FONT, 20))
+        .set_label_area_size(LabelAreaPosition::Left, 70)
+        .set_label_area_size(LabelAreaPosition::Right, 70)
+        .set_label_area_size(LabelAreaPosition::Bottom, 40)
+        .build_cartesian_2d((0..names.len() - 1).into_segmented(), 0.0..y_max)?;
+
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .x_label_formatter(&|v| match v {
+            SegmentValue::CenterOf(index) => names.get(*index).copied().unwrap_or("").to_string(),
+            _ => String::new(),
+        })
+        .y_label_formatter(&|v| metric.format_label(*v, y_max))
+        .y_labels(20)
+        .y_desc(metric.y_desc(y_label, y_max))
+        .x_desc("Series")
+        .draw()?;
+
+    for (index, value) in values.iter().enumerate() {
+        chart.draw_series(
+            Histogram::vertical(&chart)
+                .style(series_color(index).filled())
+                .margin(20)
+                .data([(index, *value)]),
+        )?;
+    }
+
+    root.present()?;
+
+    Ok(())
+}
+
+fn draw_timeseries_plot<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    series: &[(&str, Vec<Duration>)],
+    window: usize,
+    percentiles: bool,
+    caption: &str,
+) -> Result<(), Box<dyn Error>>
+where
+    DB::ErrorType: 'static,
+{
+    let nanos: Vec<Vec<f64>> = series
+        .iter()
+        .map(|(_, latencies)| latencies.iter().map(|l| l.as_nanos() as f64).collect())
+        .collect();
+
+    root.fill(&WHITE)?;
+
+    // Starts at zero so a drift reads in proportion to the latency itself
+    let x_max = nanos.iter().map(Vec::len).max().unwrap_or(0).max(2) - 1;
+    let y_max = nanos.iter().flatten().copied().fold(0.0, f64::max);
+    let y_max = if y_max > 0.0 { y_max * 1.1 } else { 1.0 };
+    let metric = PlotMetric::Latency;
+
+    let mut chart = ChartBuilder::on(&root)
+        .margin(10)
+        .caption(caption, (FONT, 20))
+        .set_label_area_size(LabelAreaPosition::Left, 70)
+        .set_label_area_size(LabelAreaPosition::Right, 70)
+        .set_label_area_size(LabelAreaPosition::Bottom, 40)
+        .build_cartesian_2d(0..x_max, 0.0..y_max)?;
+
+    chart
+        .configure_mesh()
+        .disable_y_mesh()
+        .y_label_formatter(&|v| metric.format_label(*v, y_max))
+        .x_labels(20)
+        .y_labels(20)
+        .y_desc(metric.y_desc("Latency", y_max))
+        .x_desc("Call")
+        .draw()?;
+
+    for (index, ((name, latencies), values)) in series.iter().zip(&nanos).enumerate() {
+        let color = series_color(index);
+        chart.draw_series(
+            values
+                .iter()
+                .enumerate()
+                .map(|(call, value)| Circle::new((call, *value), 2, color.mix(0.3).filled())),
+        )?;
+        chart
+            .draw_series(LineSeries::new(
+                moving_average(values, window).into_iter().enumerate(),
+                color.stroke_width(2),
+            ))?
+            .label(format!("{name} (moving average of {window})"))
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+
+        if percentiles && !latencies.is_empty() {
+            let mut sorted: Vec<f64> = latencies.iter().map(Duration::as_secs_f64).collect();
+            sorted.sort_by(f64::total_cmp);
+            for pct in TREND_PERCENTILES {
+                let value = percentile(&sorted, pct).as_nanos() as f64;
+                chart.draw_series(DashedLineSeries::new(
+                    [(0, value), (x_max, value)],
+                    6,
+                    4,
+                    color.stroke_width(1),
+                ))?;
+                // Only name the series when there's more than one line at each percentile
+                let label = if series.len() > 1 {
+                    format!("{name} p{pct}")
+                } else {
+                    format!("p{pct}")
+                };
+                let style = (FONT, 12)
+                    .into_font()
+                    .color(&color)
+                    .pos(Pos::new(HPos::Right, VPos::Bottom));
+                chart.draw_series([Text::new(label, (x_max, value), style)])?;
+            }
+        }
+    }
+
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::UpperLeft)
+        .label_font((FONT, 13))
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+
+    root.present()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legend_shows_each_series() {
+        let mut records = Vec::new();
+        for (name, latency) in [("direct", 1_000_000), ("proxy", 3_000_000)] {
+            for payload_size in [1024, 4096, 16384] {
+                records.push(Measurement {
+                    name: name.to_string(),
+                    latency: latency + payload_size * 10,
+                    payload_size,
+                    min_latency: None,
+                    max_latency: None,
+                    first_byte: None,
+                });
+            }
+        }
+
+        let path = std::env::temp_dir().join("net-bench-legend-test.svg");
+        write_plot(
+            &records,
+            "Compare",
+            "Average latency",
+            path.to_str().unwrap(),
+            PlotMetric::Latency,
+            PlotKind::Line,
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        )
+        .unwrap();
+
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("direct"));
+        assert!(svg.contains("proxy"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    fn plot_to_temp(records: &[Measurement], file_name: &str) -> Result<String, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(file_name);
+        let result = write_plot(
+            records,
+            "Test",
+            "Average latency",
+            path.to_str().unwrap(),
+            PlotMetric::Latency,
+            PlotKind::Line,
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        );
+        let svg = std::fs::read_to_string(&path).unwrap_or_default();
+        let _ = std::fs::remove_file(&path);
+        result.map(|_| svg)
+    }
+
+    fn measurement(latency: u64, payload_size: u64) -> Measurement {
+        Measurement {
+            name: "Request".to_string(),
+            latency,
+            payload_size,
+            min_latency: None,
+            max_latency: None,
+            first_byte: None,
+        }
+    }
+
+    #[test]
+    fn test_plot_no_records() {
+        let result = plot_to_temp(&[], "net-bench-empty-test.svg");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_plot_single_record() {
+        let svg = plot_to_temp(&[measurement(1_000_000, 1024)], "net-bench-single-test.svg");
+        assert!(svg.unwrap().contains("Request"));
+    }
+
+    #[test]
+    fn test_plot_equal_latencies() {
+        let records: Vec<_> = [1024, 2048, 4096]
+            .into_iter()
+            .map(|size| measurement(2_000_000, size))
+            .collect();
+        let svg = plot_to_temp(&records, "net-bench-equal-test.svg");
+        assert!(svg.unwrap().contains("Request"));
+
+        let zeros: Vec<_> = [1024, 2048]
+            .into_iter()
+            .map(|size| measurement(0, size))
+            .collect();
+        assert!(plot_to_temp(&zeros, "net-bench-zero-test.svg").is_ok());
+    }
+
+    #[test]
+    fn test_plot_error_bars() {
+        let records: Vec<_> = [1024, 4096]
+            .into_iter()
+            .map(|size| Measurement {
+                min_latency: Some(500_000),
+                max_latency: Some(9_000_000),
+                ..measurement(2_000_000, size)
+            })
+            .collect();
+        let with_spread = plot_to_temp(&records, "net-bench-error-bar-test.svg").unwrap();
+
+        let without: Vec<_> = [1024, 4096]
+            .into_iter()
+            .map(|size| measurement(2_000_000, size))
+            .collect();
+        let without_spread = plot_to_temp(&without, "net-bench-no-error-bar-test.svg").unwrap();
+
+        // Each error bar adds line segments that a plain line plot doesn't have
+        assert!(with_spread.matches("<line").count() > without_spread.matches("<line").count());
+    }
+
+    #[test]
+    fn test_plot_first_byte_series() {
+        let records: Vec<_> = [1024, 4096]
+            .into_iter()
+            .map(|size| Measurement {
+                first_byte: Some(500_000),
+                ..measurement(2_000_000, size)
+            })
+            .collect();
+        let svg = plot_to_temp(&records, "net-bench-first-byte-test.svg").unwrap();
+        assert!(svg.contains("Request (first byte)"));
+
+        // Only latency has a first byte counterpart
+        let series = with_first_byte_series(&records, PlotMetric::Throughput);
+        assert_eq!(series.len(), records.len());
+    }
+
+    #[test]
+    fn test_comparison_plot_marks_regressions() {
+        let records = vec![measurement(1_000_000, 1024), measurement(2_000_000, 4096)];
+        let path = std::env::temp_dir().join("net-bench-compare-test.svg");
+        write_comparison_plot(
+            &records,
+            &[&records[1]],
+            "Compare",
+            path.to_str().unwrap(),
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        )
+        .unwrap();
+
+        let svg = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(svg.matches("<circle").count(), 1);
+        assert!(svg.contains("#FF0000"));
+    }
+
+    #[test]
+    fn test_latency_unit_follows_range() {
+        assert_eq!(TimeUnit::for_nanos(800.0), TimeUnit::Nanos);
+        assert_eq!(TimeUnit::for_nanos(45_000.0), TimeUnit::Micros);
+        assert_eq!(TimeUnit::for_nanos(3_000_000.0), TimeUnit::Millis);
+        assert_eq!(TimeUnit::for_nanos(2e9), TimeUnit::Secs);
+
+        let metric = PlotMetric::Latency;
+        assert_eq!(metric.format_label(500.0, 900.0), "500.0 ns");
+        assert_eq!(metric.format_label(12_500.0, 50_000.0), "12.5 µs");
+        assert_eq!(
+            metric.y_desc("Average latency", 50_000.0),
+            "Average latency (µs)"
+        );
+
+        let svg = plot_to_temp(&[measurement(40_000, 1024)], "net-bench-unit-test.svg").unwrap();
+        assert!(svg.contains("µs"));
+        assert!(!svg.contains("0.0 ms"));
+    }
+
+    #[test]
+    fn test_plot_resolution() {
+        let path = std::env::temp_dir().join("net-bench-resolution-test.svg");
+        write_plot(
+            &[measurement(1_000_000, 1024)],
+            "Test",
+            "Average latency",
+            path.to_str().unwrap(),
+            PlotMetric::Latency,
+            PlotKind::Line,
+            (1200, 600),
+        )
+        .unwrap();
+
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("width=\"1200\""));
+        assert!(svg.contains("height=\"600\""));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_bar_plot_labels_each_group() {
+        let records: Vec<_> = [
+            ("direct", 1_000_000),
+            ("proxy", 3_000_000),
+            ("tls", 2_000_000),
+        ]
+        .into_iter()
+        .map(|(name, latency)| Measurement {
+            name: name.to_string(),
+            latency,
+            payload_size: 1024,
+            min_latency: None,
+            max_latency: None,
+            first_byte: None,
+        })
+        .collect();
+
+        let path = std::env::temp_dir().join("net-bench-bar-test.svg");
+        write_plot(
+            &records,
+            "Compare",
+            "Average latency",
+            path.to_str().unwrap(),
+            PlotMetric::Latency,
+            PlotKind::Bar,
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        )
+        .unwrap();
+
+        let svg = std::fs::read_to_string(&path).unwrap();
+        for name in ["direct", "proxy", "tls"] {
+            assert!(svg.contains(name));
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_save_and_load_measurements() {
+        let records = vec![
+            Measurement {
+                name: "Request".to_string(),
+                latency: 1_500_000,
+                payload_size: 1024,
+                min_latency: Some(1_000_000),
+                max_latency: Some(2_000_000),
+                first_byte: None,
+            },
+            Measurement {
+                name: "Request".to_string(),
+                latency: 2_500_000,
+                payload_size: 2048,
+                min_latency: None,
+                max_latency: None,
+                first_byte: None,
+            },
+        ];
+
+        let path = std::env::temp_dir().join("net-bench-measurements-test.json");
+        save_measurements(&path, &records).unwrap();
+        let loaded = load_measurements(&path).unwrap();
+        assert_eq!(loaded, records);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let values = [1.0, 3.0, 5.0, 7.0];
+        // The first values average over what has been seen so far
+        assert_eq!(moving_average(&values, 2), [1.0, 2.0, 4.0, 6.0]);
+        assert_eq!(moving_average(&values, 10), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(moving_average(&values, 1), values);
+    }
+
+    #[test]
+    fn test_timeseries_plot() {
+        let latencies: Vec<_> = (1..=50).map(|i| Duration::from_micros(100 + i)).collect();
+        let path = std::env::temp_dir().join("net-bench-trend-test.svg");
+        let series = [("direct", latencies.clone()), ("proxy", latencies)];
+        write_timeseries_plot(
+            &series,
+            10,
+            false,
+            "Trend",
+            path.to_str().unwrap(),
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        )
+        .unwrap();
+
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("proxy (moving average of 10)"));
+        assert!(svg.contains("µs"));
+        assert!(!svg.contains("p99"));
+        let _ = std::fs::remove_file(&path);
+
+        let empty = [("direct", Vec::new())];
+        assert!(
+            write_timeseries_plot(&empty, 10, false, "Trend", "unused.svg", (200, 200)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_timeseries_plot_percentiles() {
+        let latencies: Vec<_> = (1..=100).map(Duration::from_micros).collect();
+        let path = std::env::temp_dir().join("net-bench-trend-percentiles-test.svg");
+        write_timeseries_plot(
+            &[("direct", latencies.clone())],
+            10,
+            true,
+            "Trend",
+            path.to_str().unwrap(),
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        )
+        .unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        for label in ["p50", "p95", "p99"] {
+            assert!(
+                svg.lines().any(|line| line.trim() == label),
+                "missing {label}"
+            );
+        }
+
+        // Each series gets its own lines, named so they can be told apart
+        let series = [("direct", latencies.clone()), ("proxy", latencies)];
+        write_timeseries_plot(
+            &series,
+            10,
+            true,
+            "Trend",
+            path.to_str().unwrap(),
+            (PLOT_WIDTH, PLOT_HEIGHT),
+        )
+        .unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("direct p99"));
+        assert!(svg.contains("proxy p50"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
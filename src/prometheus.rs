@@ -0,0 +1,213 @@
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::LatencyMeasurement;
+
+/// One measured series of a run, e.g. one keep-alive setting or one payload size, told apart
+/// from the others by its labels.
+pub struct PromSeries<'a> {
+    pub labels: Vec<(&'static str, String)>,
+    pub latency: &'a LatencyMeasurement,
+}
+
+/// Reads one metric's value from a series' measurement.
+type Value = fn(&LatencyMeasurement) -> f64;
+
+/// The quantiles reported for each series, with the label value Prometheus summaries use.
+const QUANTILES: [(&str, Value); 3] = [
+    ("0.5", |latency| latency.p50.as_secs_f64()),
+    ("0.9", |latency| latency.p90.as_secs_f64()),
+    ("0.99", |latency| latency.p99.as_secs_f64()),
+];
+
+/// A gauge per series, taken from its measurement.
+const GAUGES: [(&str, &str, Value); 4] = [
+    (
+        "netbench_latency_mean_seconds",
+        "Mean latency of the measured requests.",
+        |latency| latency.latency.as_secs_f64(),
+    ),
+    (
+        "netbench_latency_min_seconds",
+        "Fastest measured request.",
+        |latency| latency.min.as_secs_f64(),
+    ),
+    (
+        "netbench_latency_max_seconds",
+        "Slowest measured request.",
+        |latency| latency.max.as_secs_f64(),
+    ),
+    (
+        "netbench_requests_per_second",
+        "Measured requests completed per second.",
+        |latency| latency.requests_per_second,
+    ),
+];
+
+/// A counter per series, taken from its measurement.
+const COUNTERS: [(&str, &str, Value); 2] = [
+    (
+        "netbench_requests_total",
+        "Requests measured, after the warmups.",
+        |latency| latency.iterations as f64,
+    ),
+    (
+        "netbench_failures_total",
+        "Measured requests that failed.",
+        |latency| latency.failures as f64,
+    ),
+];
+
+/// Formats labels as `{name="value",...}`, escaping values as the exposition format requires.
+fn label_set(labels: &[(&str, String)], extra: Option<(&str, &str)>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// The metrics for every series in Prometheus' text exposition format, with each metric's
+/// `HELP` and `TYPE` given once ahead of its series.
+fn exposition(series: &[PromSeries], timestamp: f64) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP netbench_latency_seconds Latency of the measured requests.\n");
+    out.push_str("# TYPE netbench_latency_seconds summary\n");
+    for s in series {
+        for (quantile, value) in QUANTILES {
+            let labels = label_set(&s.labels, Some(("quantile", quantile)));
+            let _ = writeln!(out, "netbench_latency_seconds{labels} {}", value(s.latency));
+        }
+        // The count and sum cover the samples kept after outlier rejection, as the quantiles do
+        let labels = label_set(&s.labels, None);
+        let sum: f64 = s.latency.samples.iter().map(|d| d.as_secs_f64()).sum();
+        let _ = writeln!(out, "netbench_latency_seconds_sum{labels} {sum}");
+        let count = s.latency.samples.len();
+        let _ = writeln!(out, "netbench_latency_seconds_count{labels} {count}");
+    }
+
+    for (name, help, value) in GAUGES {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge");
+        for s in series {
+            let labels = label_set(&s.labels, None);
+            let _ = writeln!(out, "{name}{labels} {}", value(s.latency));
+        }
+    }
+
+    for (name, help, value) in COUNTERS {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for s in series {
+            let labels = label_set(&s.labels, None);
+            let _ = writeln!(out, "{name}{labels} {}", value(s.latency));
+        }
+    }
+
+    out.push_str("# HELP netbench_last_run_timestamp_seconds When the run finished.\n");
+    out.push_str("# TYPE netbench_last_run_timestamp_seconds gauge\n");
+    let _ = writeln!(out, "netbench_last_run_timestamp_seconds {timestamp}");
+    out
+}
+
+/// Where the metrics are written before being renamed into place. It doesn't end in `.prom`,
+/// so node_exporter's textfile collector skips it.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Writes the metrics of a run to `path`, replacing the previous run's. They go to a file
+/// beside it first and are then renamed over it, so a collector reading the file never sees
+/// a partial write.
+pub fn write_prometheus(path: &Path, series: &[PromSeries]) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since| since.as_secs_f64());
+    let temp = temp_path(path);
+
+    let mut file = File::create(&temp)?;
+    file.write_all(exposition(series, timestamp).as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::measure_latency_concurrent;
+    use crate::util::MeasureConfig;
+
+    fn measured() -> LatencyMeasurement {
+        let config = MeasureConfig {
+            warmup: 0,
+            ..MeasureConfig::default()
+        };
+        measure_latency_concurrent(&config, 1, || Ok::<(), &str>(()))
+    }
+
+    #[test]
+    fn test_exposition_format() {
+        let latency = measured();
+        let series = [PromSeries {
+            labels: vec![
+                ("name", "Request".to_string()),
+                ("url", "http://a/\"b\"".to_string()),
+            ],
+            latency: &latency,
+        }];
+        let text = exposition(&series, 1.5);
+
+        let labels = r#"name="Request",url="http://a/\"b\"""#;
+        assert!(text.contains(&format!(
+            "netbench_latency_seconds{{{labels},quantile=\"0.99\"}} "
+        )));
+        assert!(text.contains(&format!(
+            "netbench_latency_seconds_count{{{labels}}} {}",
+            latency.samples.len()
+        )));
+        assert!(text.contains(&format!(
+            "netbench_requests_total{{{labels}}} {}",
+            latency.iterations
+        )));
+        assert!(text.contains("# TYPE netbench_failures_total counter\n"));
+        assert!(text.ends_with("netbench_last_run_timestamp_seconds 1.5\n"));
+        // Each metric's type is declared once
+        assert_eq!(text.matches("# TYPE netbench_latency_seconds ").count(), 1);
+    }
+
+    #[test]
+    fn test_write_replaces_file() {
+        let path = std::env::temp_dir().join("net-bench-prometheus-test.prom");
+        std::fs::write(&path, "stale").unwrap();
+
+        let latency = measured();
+        let series = [PromSeries {
+            labels: Vec::new(),
+            latency: &latency,
+        }];
+        write_prometheus(&path, &series).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# HELP netbench_latency_seconds "));
+        assert!(text.contains("netbench_latency_seconds{quantile=\"0.5\"} "));
+        assert!(!temp_path(&path).exists());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,71 +1,526 @@
 // Copyright (c) Microsoft Corporation. All Rights Reserved.
 
+#[cfg(windows)]
 use httpsys::{HttpInitializer, Request, RequestQueue, Response, ServerSession, UrlGroup};
+use log::{debug, error, info, log_enabled, trace, warn, Level};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+#[cfg(windows)]
+use reqwest::StatusCode;
 use reqwest::Url;
-use std::{collections::HashMap, sync::Arc};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal;
 use tokio::sync::broadcast;
+#[cfg(windows)]
 use windows::{
     core::HSTRING,
     Win32::Networking::HttpServer::{
-        HttpHeaderContentType, HTTP_RECEIVE_HTTP_REQUEST_FLAGS, HTTP_REQUEST_V2,
+        HttpHeaderContentLength, HttpHeaderContentType, HTTP_RECEIVE_HTTP_REQUEST_FLAGS,
+        HTTP_SEND_RESPONSE_FLAG_MORE_DATA,
     },
 };
 
+#[cfg(windows)]
 use crate::httpsys;
 
-async fn return_response(queue: &RequestQueue, req: &HTTP_REQUEST_V2, result_text: &str) {
-    let id = req.Base.RequestId;
+/// What a handler wants sent back for a request.
+pub(crate) struct HandlerResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+    /// Shut the server down once this response has been sent.
+    pub is_kill: bool,
+    /// How long to wait before sending the response. Only the worker handling this request
+    /// waits, so other requests are still answered in the meantime.
+    pub delay: Duration,
+    /// Sent in pieces in place of `body` when set.
+    pub stream: Option<StreamedBody>,
+}
+
+/// Bytes sent per piece of a streamed response.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A generated body of `len` bytes sent as repeats of one chunk, so a large response costs one
+/// chunk of memory however big it is, and each piece is sent as soon as the previous one has
+/// gone.
+#[derive(Clone)]
+pub(crate) struct StreamedBody {
+    chunk: Arc<Vec<u8>>,
+    pub len: usize,
+}
+
+impl StreamedBody {
+    /// Generates random alphanumeric text for the chunk.
+    pub fn new(len: usize) -> Self {
+        let chunk = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(len.min(STREAM_CHUNK_SIZE))
+            .collect();
+        StreamedBody {
+            chunk: Arc::new(chunk),
+            len,
+        }
+    }
+
+    /// The pieces to send in order, every one full length but the last.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        let chunk_size = self.chunk.len().max(1);
+        (0..self.len)
+            .step_by(chunk_size)
+            .map(move |start| &self.chunk[..chunk_size.min(self.len - start)])
+    }
+}
+
+impl HandlerResponse {
+    /// A 200 response with a JSON content type, which is what handlers returned before they
+    /// could choose.
+    pub fn ok(body: &str) -> Self {
+        HandlerResponse {
+            status: 200,
+            content_type: "application/json".to_string(),
+            body: body.as_bytes().to_vec(),
+            is_kill: false,
+            delay: Duration::ZERO,
+            stream: None,
+        }
+    }
+
+    /// A 200 response carrying the contents of a file, typed by its extension.
+    pub fn file(path: &Path, contents: Vec<u8>) -> Self {
+        HandlerResponse {
+            status: 200,
+            content_type: content_type_for(path).to_string(),
+            body: contents,
+            is_kill: false,
+            delay: Duration::ZERO,
+            stream: None,
+        }
+    }
+
+    /// A 200 response sending a request body straight back, typed as the request was.
+    pub fn echo(body: Vec<u8>, content_type: Option<String>) -> Self {
+        HandlerResponse {
+            status: 200,
+            content_type: content_type.unwrap_or_else(|| "application/octet-stream".to_string()),
+            body,
+            is_kill: false,
+            delay: Duration::ZERO,
+            stream: None,
+        }
+    }
+
+    /// A 200 response streaming a generated body.
+    pub fn streamed(body: StreamedBody) -> Self {
+        HandlerResponse {
+            status: 200,
+            content_type: "text/plain".to_string(),
+            body: Vec::new(),
+            is_kill: false,
+            delay: Duration::ZERO,
+            stream: Some(body),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        HandlerResponse {
+            status: 404,
+            content_type: "text/plain".to_string(),
+            body: b"Not Found".to_vec(),
+            is_kill: false,
+            delay: Duration::ZERO,
+            stream: None,
+        }
+    }
+
+    /// Size of the body as sent, streamed or not.
+    pub fn body_len(&self) -> usize {
+        self.stream
+            .as_ref()
+            .map_or(self.body.len(), |stream| stream.len)
+    }
+}
+
+/// Guesses a content type from a file extension, falling back to a generic binary type.
+pub(crate) fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("txt") => "text/plain",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(windows)]
+async fn return_response(queue: &RequestQueue, req: &Request, result: &HandlerResponse) {
+    let id = req.id();
 
     let mut resp = Response::default();
-    resp.raw.Base.StatusCode = 200;
-    let reason = "OK";
+    resp.raw.Base.StatusCode = result.status;
+    let reason = StatusCode::from_u16(result.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
     resp.raw.Base.pReason = windows::core::PCSTR(reason.as_ptr());
     resp.raw.Base.ReasonLength = reason.len() as u16;
 
-    let content_type = "application/json";
+    let content_type = &result.content_type;
     resp.raw.Base.Headers.KnownHeaders[HttpHeaderContentType.0 as usize].RawValueLength =
         content_type.len() as u16;
     resp.raw.Base.Headers.KnownHeaders[HttpHeaderContentType.0 as usize].pRawValue =
         ::windows::core::PCSTR(content_type.as_ptr());
 
-    resp.add_body_chunk(result_text);
+    // The length is sent up front, as HTTP.SYS doesn't frame chunked encoding itself
+    let content_length = result.body_len().to_string();
+    let header = &mut resp.raw.Base.Headers.KnownHeaders[HttpHeaderContentLength.0 as usize];
+    header.RawValueLength = content_length.len() as u16;
+    header.pRawValue = ::windows::core::PCSTR(content_length.as_ptr());
 
     let flags = 0u32; // HTTP_SEND_RESPONSE_FLAG_DISCONNECT;
 
+    // A HEAD request gets the headers a GET would, length included, with no body after them
+    let head = req.method() == "HEAD";
+
+    // A streamed body goes after the headers, one HttpSendResponseEntityBody per chunk
+    let stream = result
+        .stream
+        .as_ref()
+        .filter(|stream| stream.len > 0 && !head);
+    let flags = if stream.is_some() {
+        flags | HTTP_SEND_RESPONSE_FLAG_MORE_DATA
+    } else {
+        if !head {
+            resp.add_body_chunk(&result.body);
+        }
+        flags
+    };
+
     let err = queue.async_send_response(id, flags, &resp).await;
     if err.is_err() {
-        println!("handle_request failed: {:?}", err.err());
+        error!("Sending the response failed: {:?}", err.err());
+        return;
+    }
+
+    if let Some(stream) = stream {
+        let mut chunks = stream.chunks().peekable();
+        while let Some(chunk) = chunks.next() {
+            let more = if chunks.peek().is_some() {
+                HTTP_SEND_RESPONSE_FLAG_MORE_DATA
+            } else {
+                0
+            };
+            if let Err(e) = queue.async_send_entity_body(id, more, chunk).await {
+                error!("Sending a body chunk failed: {:?}", e);
+                return;
+            }
+        }
     }
 }
 
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// What a handler is told about the request it answers.
+pub(crate) struct RequestContext {
+    pub method: String,
+    /// The raw URL as the client sent it, including any query.
+    pub url: String,
+    /// Header names keep whatever case the backend reports them in, so look them up with
+    /// `header`.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl RequestContext {
+    /// The value of a request header, with the name matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A request handler, given the request's method, URL, headers and body.
+pub(crate) type Handler = Box<dyn Fn(&RequestContext) -> HandlerResponse + Send + Sync>;
+
+/// A stateless handler, convenient for building a `Vec` of non-capturing closures.
+pub(crate) type HandlerFn = fn(&RequestContext) -> HandlerResponse;
+
+/// How many requests have been handled for one registered URL.
+struct RequestCounter {
+    url: String,
+    count: AtomicU64,
+}
+
+impl RequestCounter {
+    fn new(url: &Url) -> Self {
+        RequestCounter {
+            url: url.path().to_string(),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+type RequestCounts = Arc<HashMap<u64, RequestCounter>>;
+
+/// The per-URL counts as a JSON object keyed by URL.
+fn stats_json(counts: &HashMap<u64, RequestCounter>) -> String {
+    let counts: BTreeMap<&str, u64> = counts
+        .values()
+        .map(|counter| (counter.url.as_str(), counter.count.load(Ordering::Relaxed)))
+        .collect();
+    serde_json::to_string(&counts).unwrap_or_default()
+}
+
+/// Logs one line per handled request at debug level, so `-vv` shows it. `handler` is the time
+/// spent in the handler alone and `total` also covers reading the body, any delay and sending the
+/// response, so the difference shows the server's own overhead.
+fn log_request(
+    method: &str,
+    url: &str,
+    result: &HandlerResponse,
+    handler: Duration,
+    total: Duration,
+) {
+    debug!(
+        "request method={} url={} status={} bytes={} handler_us={} total_us={}",
+        method,
+        url,
+        result.status,
+        result.body_len(),
+        handler.as_micros(),
+        total.as_micros()
+    );
+}
+
+/// How long shutdown waits for requests already being answered, unless set otherwise.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for the tasks still answering requests once shutdown has begun, giving up on any
+/// still running at `timeout` so that a stuck request can't hold the server open. On Windows an
+/// aborted task cancels the IO it has outstanding with HTTP.SYS, and waits for it to finish,
+/// as it is dropped.
+async fn drain<T>(tasks: Vec<tokio::task::JoinHandle<T>>, timeout: Duration) -> Vec<T> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut finished = Vec::new();
+    let mut abandoned = 0;
+    for mut task in tasks {
+        match tokio::time::timeout_at(deadline, &mut task).await {
+            Ok(Ok(result)) => finished.push(result),
+            Ok(Err(e)) => error!("Worker failed: {:?}", e),
+            Err(_) => {
+                task.abort();
+                abandoned += 1;
+            }
+        }
+    }
+    if abandoned > 0 {
+        warn!("Drain timed out after {timeout:?}, abandoning {abandoned} busy workers");
+    }
+    finished
+}
+
+/// Answers requests on HTTP.SYS on Windows, and on hyper elsewhere so that test mode also works
+/// on developer machines. Both register the same URLs and handlers.
 pub(crate) struct Server {
     worker: Option<std::thread::JoinHandle<()>>,
+    #[cfg(windows)]
     request_queue: Option<Arc<RequestQueue>>,
     kill_tx: Option<broadcast::Sender<String>>,
+    #[cfg(windows)]
     init: Option<HttpInitializer>,
+    #[cfg(windows)]
     session: Option<Arc<ServerSession>>,
+    #[cfg(windows)]
     group: Option<Arc<UrlGroup>>,
+    workers: usize,
+    drain_timeout: Duration,
+    stats_url: Option<Url>,
+    echo_url: Option<Url>,
+    #[cfg(unix)]
+    uds_path: Option<PathBuf>,
+    /// URLs bound by `listen`, with their listeners, which `start` serves rather than binding
+    /// their host and port again.
+    #[cfg(not(windows))]
+    listened: Vec<Url>,
+    #[cfg(not(windows))]
+    listeners: Vec<std::net::TcpListener>,
+}
+
+/// Tells every receive loop to stop. Nothing may be listening, e.g. when no handlers were
+/// defined or the workers have already stopped, which is reported rather than treated as an
+/// error.
+fn send_kill(tx: &broadcast::Sender<String>) {
+    if tx.send("kill".to_string()).is_err() {
+        warn!("Kill signal not delivered: no workers are listening");
+    }
 }
 
 impl Drop for Server {
     fn drop(&mut self) {
-        if let Some(tx) = &self.kill_tx {
-            tx.send("kill".to_string());
+        // Only signal a worker that is still running; one that already stopped needs no kill
+        if let (Some(tx), Some(_)) = (&self.kill_tx, &self.worker) {
+            send_kill(tx);
         }
 
         if let Some(handle) = self.worker.take() {
             handle.join().unwrap();
         }
 
-        drop(self.request_queue.take());
-        drop(self.group.take());
-        drop(self.session.take());
-        drop(self.init.take());
+        #[cfg(windows)]
+        {
+            drop(self.request_queue.take());
+            drop(self.group.take());
+            drop(self.session.take());
+            drop(self.init.take());
+        }
         drop(self.kill_tx.take());
     }
 }
 
+/// Receives and answers requests one at a time until the kill channel fires. Several of these
+/// run at once so that many receives are outstanding against the queue. Requests to `echo_id`
+/// are answered with their own body and content type, moved rather than copied out of the
+/// request as a handler, which only borrows it, would have to.
+#[cfg(windows)]
+async fn receive_loop(
+    rq: Arc<RequestQueue>,
+    handlers: Arc<HashMap<u64, Handler>>,
+    echo_id: Option<u64>,
+    counts: RequestCounts,
+    term_tx: Option<broadcast::Sender<String>>,
+    mut kill_channel: broadcast::Receiver<String>,
+) {
+    loop {
+        let mut req = Request::default();
+
+        // Only the receive is raced against shutdown. Once a request has arrived it is
+        // handled and answered in full before the loop checks for shutdown again, and a kill
+        // is checked first so that nothing new is received while draining.
+        let received = tokio::select! {
+            biased;
+            _ = kill_channel.recv() => {
+                break;
+            },
+            received = rq.async_receive_request(
+                0,
+                HTTP_RECEIVE_HTTP_REQUEST_FLAGS::default(),
+                &mut req,
+            ) => received,
+        };
+
+        if received.is_err() {
+            error!("Receiving a request failed: {:?}", received.err());
+        } else {
+            let url = req.url();
+            let url_context = req.url_context();
+            trace!(
+                "Received {} {url} for URL context {url_context}",
+                req.method()
+            );
+
+            let handler = handlers.get(&url_context);
+            if handler.is_some() || echo_id == Some(url_context) {
+                let start = Instant::now();
+                if let Some(counter) = counts.get(&url_context) {
+                    counter.count.fetch_add(1, Ordering::Relaxed);
+                }
+
+                let body = rq
+                    .async_receive_entity_body(&req)
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Receiving a request body failed: {:?}", e);
+                        Vec::new()
+                    });
+                let mut context = RequestContext {
+                    method: req.method(),
+                    url,
+                    headers: req.headers(),
+                    body,
+                };
+                let handler_start = Instant::now();
+                let result = match handler {
+                    Some(handler) => handler(&context),
+                    None => {
+                        let content_type = context.header("Content-Type").map(str::to_string);
+                        HandlerResponse::echo(std::mem::take(&mut context.body), content_type)
+                    }
+                };
+                let handler_time = handler_start.elapsed();
+
+                if result.is_kill {
+                    if let Some(term_tx) = &term_tx {
+                        send_kill(term_tx);
+                    } else {
+                        error!("term_tx is None, cannot send kill signal");
+                    }
+                }
+
+                if !result.delay.is_zero() {
+                    tokio::time::sleep(result.delay).await;
+                }
+
+                return_response(&rq, &req, &result).await;
+                if log_enabled!(Level::Debug) {
+                    let total = start.elapsed();
+                    log_request(&context.method, &context.url, &result, handler_time, total);
+                }
+            } else {
+                info!(
+                    "Unknown URL context: {} for {} {}",
+                    url_context,
+                    req.method(),
+                    url
+                );
+                return_response(&rq, &req, &HandlerResponse::not_found()).await;
+            }
+        }
+    }
+}
+
 impl Server {
+    #[cfg(windows)]
     pub fn new() -> Self {
         let init = HttpInitializer::default();
         let session = Arc::<ServerSession>::default();
@@ -81,97 +536,598 @@ impl Server {
             init: Some(init),
             session: Some(session),
             group: Some(url_group),
+            workers: 1,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            stats_url: None,
+            echo_url: None,
         }
     }
 
+    /// Sets how many receives are kept outstanding against the request queue. Must be called
+    /// before `define_handlers`.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers;
+    }
+
+    /// Sets how long shutdown waits for requests already being answered before giving up on
+    /// them. Must be called before `define_handlers`.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Serves the number of requests handled per URL as JSON on `url`. Must be called before
+    /// `define_handlers`.
+    pub fn set_stats_url(&mut self, url: Url) {
+        self.stats_url = Some(url);
+    }
+
+    /// Answers requests to `url` with the body they were sent, using the same content type.
+    /// Must be called before `define_handlers`.
+    pub fn set_echo_url(&mut self, url: Url) {
+        self.echo_url = Some(url);
+    }
+
+    /// Also answers requests on a Unix domain socket at `path`, as they would be answered on
+    /// the first URL's port, so local benchmarks can leave out the TCP loopback. A socket left
+    /// at `path` by an earlier run is replaced. Must be called before `define_handlers`.
+    #[cfg(unix)]
+    pub fn set_uds_path(&mut self, path: PathBuf) {
+        self.uds_path = Some(path);
+    }
+
+    /// Listens on `url` ahead of `define_handlers`, returning it with the port requests arrive
+    /// on. For port 0 the OS picks a free port, which is kept bound from then on so that nothing
+    /// else can take it before the handlers are defined.
+    #[cfg(not(windows))]
+    pub fn listen(&mut self, url: &Url) -> Url {
+        let listeners = bind_listeners(url);
+        let port = listeners[0]
+            .local_addr()
+            .unwrap_or_else(|e| panic!("Could not read the port of {url}: {e}"))
+            .port();
+        let mut url = url.clone();
+        url.set_port(Some(port)).expect("Failed to set port");
+        self.listened.push(url.clone());
+        self.listeners.extend(listeners);
+        url
+    }
+
+    /// Returns `url` with port 0 replaced by a port the OS reports free. HTTP.SYS can't register
+    /// port 0 itself and only listens once the handlers are defined, so another process may take
+    /// the port first.
+    #[cfg(windows)]
+    pub fn listen(&mut self, url: &Url) -> Url {
+        if url.port() != Some(0) {
+            return url.clone();
+        }
+        let host = match url.host_str() {
+            Some("+" | "*") | None => "0.0.0.0",
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        };
+        let port = std::net::TcpListener::bind((host, 0))
+            .and_then(|listener| listener.local_addr())
+            .unwrap_or_else(|e| panic!("Could not find a free port on {host}: {e}"))
+            .port();
+
+        let mut url = url.clone();
+        url.set_port(Some(port)).expect("Failed to set port");
+        url
+    }
+
     pub fn wait(&mut self) {
         if let Some(w) = self.worker.take() {
             w.join().unwrap();
         }
     }
 
+    #[cfg_attr(not(test), allow(dead_code))]
     pub fn kill(&self) {
         if let Some(tx) = &self.kill_tx {
-            tx.send("kill".to_string());
+            send_kill(tx);
         }
     }
 
-    pub fn define_handlers(&mut self, url_handlers: Vec<(&Url, fn(&str) -> (String, bool))>) {
+    /// Registers a handler per URL and starts the worker. Handlers may be plain `fn` pointers,
+    /// closures capturing state, or already boxed `Handler`s.
+    pub fn define_handlers<F>(&mut self, url_handlers: Vec<(&Url, F)>)
+    where
+        F: Fn(&RequestContext) -> HandlerResponse + Send + Sync + 'static,
+    {
         let mut next_url_id = 1000;
-        let mut handlers: HashMap<u64, fn(&str) -> (String, bool)> = HashMap::new();
+        let mut routes = Vec::new();
+        let mut handlers: HashMap<u64, Handler> = HashMap::new();
+        let mut counts = HashMap::new();
 
         for (url, handler_fn) in url_handlers {
-            if let Some(group) = &self.group {
-                group
-                    .add_url(HSTRING::from(url.as_str()), next_url_id)
-                    .unwrap();
+            routes.push((next_url_id, url.clone()));
+            handlers.insert(next_url_id, Box::new(handler_fn));
+            counts.insert(next_url_id, RequestCounter::new(url));
+            next_url_id += 1;
+        }
+
+        // The stats handler reads the counts, so it is added once they are all registered
+        let stats_id = next_url_id;
+        if let Some(url) = &self.stats_url {
+            routes.push((stats_id, url.clone()));
+            counts.insert(stats_id, RequestCounter::new(url));
+        }
+
+        let echo_id = self.echo_url.as_ref().map(|url| {
+            let echo_id = stats_id + 1;
+            routes.push((echo_id, url.clone()));
+            counts.insert(echo_id, RequestCounter::new(url));
+            echo_id
+        });
+
+        let counts: RequestCounts = Arc::new(counts);
+        if counts.contains_key(&stats_id) {
+            let stats_counts = counts.clone();
+            handlers.insert(
+                stats_id,
+                Box::new(move |_| HandlerResponse::ok(&stats_json(&stats_counts))),
+            );
+        }
+
+        self.start(routes, handlers, echo_id, counts);
+    }
 
-                handlers.insert(next_url_id, handler_fn);
-                next_url_id += 1;
+    /// Adds each route's URL to the URL group under its id, then starts a background thread
+    /// running a pool of receive loops.
+    #[cfg(windows)]
+    fn start(
+        &mut self,
+        routes: Vec<(u64, Url)>,
+        handlers: HashMap<u64, Handler>,
+        echo_id: Option<u64>,
+        counts: RequestCounts,
+    ) {
+        if let Some(group) = &self.group {
+            for (id, url) in &routes {
+                group.add_url(HSTRING::from(url.as_str()), *id).unwrap();
+                debug!("Registered {url} as URL context {id}");
             }
         }
 
         let rq = self.request_queue.clone();
         let term_tx = self.kill_tx.clone();
+        let handlers = Arc::new(handlers);
+        let workers = self.workers.max(1);
+        let drain_timeout = self.drain_timeout;
+
+        // Single background thread running a pool of receive loops
+        let handle = std::thread::spawn(move || {
+            let mut kill_channel = term_tx
+                .as_ref()
+                .map(|tx| tx.subscribe())
+                .expect("Could not subscribe to kill channel");
+            let rt = tokio::runtime::Runtime::new().unwrap();
+
+            rt.block_on(async move {
+                // Only try to receive requests if rq is Some
+                let Some(rq) = rq else {
+                    return;
+                };
+
+                let receivers: Vec<_> = (0..workers)
+                    .map(|_| {
+                        // Subscribe before spawning so a kill sent straight away isn't missed
+                        let kill_channel = term_tx
+                            .as_ref()
+                            .map(|tx| tx.subscribe())
+                            .expect("Could not subscribe to kill channel");
+                        tokio::spawn(receive_loop(
+                            rq.clone(),
+                            handlers.clone(),
+                            echo_id,
+                            counts.clone(),
+                            term_tx.clone(),
+                            kill_channel,
+                        ))
+                    })
+                    .collect();
+
+                tokio::select! {
+                    _ = kill_channel.recv() => {
+                        info!("Shutting down server.");
+                    },
+                    _ = shutdown_signal() => {
+                        info!("Shutdown signal received, shutting down server.");
+                        if let Some(term_tx) = &term_tx {
+                            send_kill(term_tx);
+                        }
+                    },
+                }
+
+                // The queue is closed once the server is dropped, after the drain
+                drain(receivers, drain_timeout).await;
+            });
+        });
+
+        self.worker = Some(handle);
+    }
+}
+
+/// A registered URL on the hyper backend, answered for requests to its port whose path is the
+/// URL's path or lies below it.
+#[cfg(not(windows))]
+struct Route {
+    port: u16,
+    path: String,
+    id: u64,
+}
+
+/// Everything a connection needs to answer requests, shared by every listener.
+#[cfg(not(windows))]
+struct Routes {
+    routes: Vec<Route>,
+    handlers: HashMap<u64, Handler>,
+    echo_id: Option<u64>,
+    counts: RequestCounts,
+    term_tx: Option<broadcast::Sender<String>>,
+}
+
+#[cfg(not(windows))]
+impl Routes {
+    /// The longest registered path matching the request, as HTTP.SYS picks the most specific
+    /// URL in a group.
+    fn find(&self, port: u16, path: &str) -> Option<u64> {
+        self.routes
+            .iter()
+            .filter(|route| route.port == port)
+            .filter(|route| {
+                path == route.path
+                    || path
+                        .strip_prefix(route.path.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+            .max_by_key(|route| route.path.len())
+            .map(|route| route.id)
+    }
+}
+
+/// Binds every address `url` resolves to, so `localhost` is answered over both IPv4 and IPv6.
+/// The wildcard hosts HTTP.SYS accepts listen on all interfaces.
+#[cfg(not(windows))]
+fn bind_listeners(url: &Url) -> Vec<std::net::TcpListener> {
+    use std::net::ToSocketAddrs;
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = match url.host_str() {
+        Some("+" | "*") | None => "0.0.0.0",
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+    };
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .unwrap_or_else(|e| panic!("Could not resolve {url}: {e}"));
+
+    // For port 0 the first address is given a port by the OS and the rest share it
+    let mut port = port;
+    let mut listeners = Vec::new();
+    for mut addr in addrs {
+        addr.set_port(port);
+        if let Ok(listener) = std::net::TcpListener::bind(addr) {
+            if port == 0 {
+                port = listener.local_addr().map_or(0, |addr| addr.port());
+            }
+            listeners.push(listener);
+        }
+    }
+    if listeners.is_empty() {
+        panic!("Could not listen on {host}:{port} for {url}");
+    }
+    for listener in &listeners {
+        listener.set_nonblocking(true).unwrap();
+        if let Ok(addr) = listener.local_addr() {
+            debug!("Listening on {addr} for {url}");
+        }
+    }
+    listeners
+}
+
+/// Listens on a Unix domain socket at `path`. Only a socket is removed to make way for it, so
+/// a mistyped path can't delete a regular file.
+#[cfg(unix)]
+fn bind_unix_listener(path: &Path) -> std::os::unix::net::UnixListener {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            panic!("Could not listen on {}: not a socket", path.display());
+        }
+        let _ = std::fs::remove_file(path);
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)
+        .unwrap_or_else(|e| panic!("Could not listen on {}: {e}", path.display()));
+    listener.set_nonblocking(true).unwrap();
+    listener
+}
+
+/// Answers one request on the hyper backend the way `receive_loop` does on HTTP.SYS. hyper
+/// writes the response after this returns, so a logged total doesn't include sending it.
+#[cfg(not(windows))]
+async fn serve_request(
+    routes: Arc<Routes>,
+    port: u16,
+    req: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+    use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, HOST};
+
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("localhost:{port}"));
+    let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let url = format!("http://{host}{path_and_query}");
+
+    let method = req.method().to_string();
+    trace!("Received {method} {url}");
+    let result = match routes.find(port, req.uri().path()) {
+        Some(id) => {
+            let start = Instant::now();
+            if let Some(counter) = routes.counts.get(&id) {
+                counter.count.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // Repeated headers are joined into one value, as HTTP.SYS does for those it parses
+            let mut headers: HashMap<String, String> = HashMap::new();
+            for (name, value) in req.headers() {
+                let Ok(value) = value.to_str() else {
+                    continue;
+                };
+                headers
+                    .entry(name.as_str().to_string())
+                    .and_modify(|joined| {
+                        joined.push_str(", ");
+                        joined.push_str(value);
+                    })
+                    .or_insert_with(|| value.to_string());
+            }
+            let body = hyper::body::to_bytes(req.into_body())
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Receiving a request body failed: {:?}", e);
+                    Default::default()
+                });
+            let mut context = RequestContext {
+                method,
+                url,
+                headers,
+                body: body.to_vec(),
+            };
+            let handler_start = Instant::now();
+            let result = match routes.handlers.get(&id) {
+                Some(handler) => handler(&context),
+                None if routes.echo_id == Some(id) => {
+                    let content_type = context.header("Content-Type").map(str::to_string);
+                    HandlerResponse::echo(std::mem::take(&mut context.body), content_type)
+                }
+                None => HandlerResponse::not_found(),
+            };
+            let handler_time = handler_start.elapsed();
+
+            if result.is_kill {
+                if let Some(term_tx) = &routes.term_tx {
+                    send_kill(term_tx);
+                }
+            }
+
+            if !result.delay.is_zero() {
+                tokio::time::sleep(result.delay).await;
+            }
+            if log_enabled!(Level::Debug) {
+                let total = start.elapsed();
+                log_request(&context.method, &context.url, &result, handler_time, total);
+            }
+            result
+        }
+        None => {
+            info!("Unknown URL: {} {}", method, url);
+            HandlerResponse::not_found()
+        }
+    };
+
+    let stream_len = result.stream.as_ref().map(|stream| stream.len);
+    let body = match result.stream {
+        Some(stream) => {
+            let (mut sender, body) = hyper::Body::channel();
+            tokio::spawn(async move {
+                for chunk in stream.chunks() {
+                    let chunk = hyper::body::Bytes::copy_from_slice(chunk);
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            body
+        }
+        None => hyper::Body::from(result.body),
+    };
+
+    let mut response = hyper::Response::new(body);
+    *response.status_mut() = hyper::StatusCode::from_u16(result.status)
+        .unwrap_or(hyper::StatusCode::INTERNAL_SERVER_ERROR);
+    if let Ok(content_type) = HeaderValue::from_str(&result.content_type) {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    if let Some(len) = stream_len {
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    Ok(response)
+}
+
+#[cfg(not(windows))]
+impl Server {
+    pub fn new() -> Self {
+        let (kill_tx, _) = broadcast::channel::<String>(1);
+
+        Server {
+            worker: None, // Will be populated later
+            kill_tx: Some(kill_tx),
+            workers: 1,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            stats_url: None,
+            echo_url: None,
+            #[cfg(unix)]
+            uds_path: None,
+            listened: Vec::new(),
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Listens on each route's host and port, then starts a background thread serving them
+    /// with hyper. Connections are served concurrently, so the worker count doesn't apply.
+    fn start(
+        &mut self,
+        routes: Vec<(u64, Url)>,
+        handlers: HashMap<u64, Handler>,
+        echo_id: Option<u64>,
+        counts: RequestCounts,
+    ) {
+        use hyper::service::{make_service_fn, service_fn};
+
+        // Listen before returning, so requests sent straight after find the port open
+        let key = |url: &Url| {
+            (
+                url.host_str().map(str::to_string),
+                url.port_or_known_default(),
+            )
+        };
+        let mut bound: Vec<_> = self.listened.iter().map(key).collect();
+        let mut listeners = std::mem::take(&mut self.listeners);
+        for (_, url) in &routes {
+            let key = key(url);
+            if !bound.contains(&key) {
+                listeners.extend(bind_listeners(url));
+                bound.push(key);
+            }
+        }
+
+        #[cfg(unix)]
+        let unix_listener = self
+            .uds_path
+            .as_ref()
+            .map(|path| (path.clone(), bind_unix_listener(path)));
+        // Requests over the socket are routed as though they came in on the first URL's port
+        #[cfg(unix)]
+        let unix_port = routes
+            .first()
+            .and_then(|(_, url)| url.port_or_known_default())
+            .unwrap_or(80);
+
+        let term_tx = self.kill_tx.clone();
+        let drain_timeout = self.drain_timeout;
+        let routes = Arc::new(Routes {
+            routes: routes
+                .into_iter()
+                .map(|(id, url)| Route {
+                    port: url.port_or_known_default().unwrap_or(80),
+                    path: url.path().trim_end_matches('/').to_string(),
+                    id,
+                })
+                .collect(),
+            handlers,
+            echo_id,
+            counts,
+            term_tx: term_tx.clone(),
+        });
 
-        // Single background thread
         let handle = std::thread::spawn(move || {
             let mut kill_channel = term_tx
                 .as_ref()
                 .map(|tx| tx.subscribe())
                 .expect("Could not subscribe to kill channel");
-            let rq = rq.as_ref();
             let rt = tokio::runtime::Runtime::new().unwrap();
-            
+
             rt.block_on(async move {
-                loop {
-                    tokio::select! {
-                        _ = async {
-                            kill_channel.recv().await
-                        } => {
-                            println!("Shutting down server.");
-                            break;
-                        },
-                        _ = async {
-                            // Only try to receive a request if rq is Some
-                            if let Some(rq) = rq {
-                                let mut req = Request::default();
-                                let err = rq
-                                    .async_receive_request(
-                                        0,
-                                        HTTP_RECEIVE_HTTP_REQUEST_FLAGS::default(),
-                                        &mut req,
-                                    )
-                                    .await;
-
-                                if err.is_err() {
-                                    println!("request fail: {:?}", err.err());
-                                } else {
-                                    let url = req.url();
-                                    let url_context = req.raw().Base.UrlContext;
-
-                                    if let Some(handler) = handlers.get(&url_context) {
-                                        let (result, is_kill) = handler(&url);
-
-                                        if is_kill {
-                                            // Check if term_tx is Some before sending
-                                            if let Some(term_tx) = &term_tx {
-                                                term_tx.send("kill".to_string()).unwrap();
-                                            } else {
-                                                // Handle the case where term_tx is None (optional)
-                                                eprintln!("Error: term_tx is None, cannot send kill signal");
-                                            }
-                                        }
-
-                                        return_response(rq, &req.raw(), &result).await;
-                                    } else {
-                                        println!("Unknown URL context: {}", url_context);
-                                    }
-                                }
+                let mut servers: Vec<_> = listeners
+                    .into_iter()
+                    .map(|listener| {
+                        let port = listener.local_addr().unwrap().port();
+                        let routes = routes.clone();
+                        let make_service = make_service_fn(move |_| {
+                            let routes = routes.clone();
+                            async move {
+                                Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                                    serve_request(routes.clone(), port, req)
+                                }))
                             }
-                        } => {}
+                        });
+
+                        // Subscribe before spawning so a kill sent straight away isn't missed
+                        let mut kill_channel = term_tx
+                            .as_ref()
+                            .map(|tx| tx.subscribe())
+                            .expect("Could not subscribe to kill channel");
+                        // Pipelined responses are flushed together, as writing each on its own
+                        // stalls every one after the first on Nagle's algorithm
+                        let server = hyper::Server::from_tcp(listener)
+                            .unwrap()
+                            .http1_pipeline_flush(true)
+                            .serve(make_service)
+                            .with_graceful_shutdown(async move {
+                                let _ = kill_channel.recv().await;
+                            });
+                        tokio::spawn(server)
+                    })
+                    .collect();
+
+                #[cfg(unix)]
+                let unix_path = unix_listener.map(|(path, listener)| {
+                    let listener = tokio::net::UnixListener::from_std(listener).unwrap();
+                    let incoming = hyper::server::accept::poll_fn(move |cx| {
+                        listener
+                            .poll_accept(cx)
+                            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+                    });
+                    let routes = routes.clone();
+                    let make_service = make_service_fn(move |_| {
+                        let routes = routes.clone();
+                        async move {
+                            Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                                serve_request(routes.clone(), unix_port, req)
+                            }))
+                        }
+                    });
+                    let mut kill_channel = term_tx
+                        .as_ref()
+                        .map(|tx| tx.subscribe())
+                        .expect("Could not subscribe to kill channel");
+                    let server = hyper::Server::builder(incoming)
+                        .http1_pipeline_flush(true)
+                        .serve(make_service)
+                        .with_graceful_shutdown(async move {
+                            let _ = kill_channel.recv().await;
+                        });
+                    servers.push(tokio::spawn(server));
+                    path
+                });
+
+                tokio::select! {
+                    _ = kill_channel.recv() => {
+                        info!("Shutting down server.");
+                    },
+                    _ = shutdown_signal() => {
+                        info!("Shutdown signal received, shutting down server.");
+                        if let Some(term_tx) = &term_tx {
+                            send_kill(term_tx);
+                        }
+                    },
+                }
+
+                // Each server stops accepting connections and finishes the requests it has
+                for result in drain(servers, drain_timeout).await {
+                    if let Err(e) = result {
+                        error!("Server failed: {:?}", e);
                     }
                 }
+                #[cfg(unix)]
+                if let Some(path) = unix_path {
+                    let _ = std::fs::remove_file(path);
+                }
             });
         });
 
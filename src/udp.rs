@@ -0,0 +1,113 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::util::{LISTENING_PREFIX, READY_LINE};
+
+/// Largest datagram that is echoed back whole.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// Bytes at the start of each payload holding its sequence number.
+const SEQUENCE_LEN: usize = std::mem::size_of::<u64>();
+
+/// Echoes every datagram received on `bind` back to its sender until the process is killed.
+/// This is a plain tokio socket loop, as HTTP.SYS has no part in UDP, so it runs anywhere.
+pub fn serve(bind: &str) -> io::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let socket = tokio::net::UdpSocket::bind(bind).await?;
+        let local_addr = socket.local_addr()?;
+        info!("UDP echo server running on {local_addr}");
+        println!("{LISTENING_PREFIX}{}", local_addr.port());
+        println!("{READY_LINE}");
+
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer).await?;
+            if let Err(e) = socket.send_to(&buffer[..len], peer).await {
+                warn!("UDP echo to {peer} failed: {e}");
+            }
+        }
+    })
+}
+
+/// Sends datagrams to an echo server one at a time and waits for each to come back.
+pub struct UdpPinger {
+    socket: UdpSocket,
+    payload: Vec<u8>,
+    buffer: Vec<u8>,
+    sequence: u64,
+}
+
+impl UdpPinger {
+    /// Connects to `target` with a `size` byte payload, at least large enough for the sequence
+    /// number. A datagram not echoed within `timeout` counts as lost.
+    pub fn new(target: SocketAddr, size: usize, timeout: Duration) -> io::Result<Self> {
+        let bind: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(target)?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        let size = size.clamp(SEQUENCE_LEN, MAX_DATAGRAM);
+        Ok(UdpPinger {
+            socket,
+            payload: vec![b'x'; size],
+            buffer: vec![0u8; MAX_DATAGRAM],
+            sequence: 0,
+        })
+    }
+
+    pub fn payload_size(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Sends one datagram and waits for its echo. Each payload starts with a sequence number,
+    /// so a late echo of an earlier datagram that was given up on isn't taken for this one.
+    pub fn ping(&mut self) -> io::Result<()> {
+        self.sequence += 1;
+        self.payload[..SEQUENCE_LEN].copy_from_slice(&self.sequence.to_le_bytes());
+        self.socket.send(&self.payload)?;
+
+        loop {
+            let len = self.socket.recv(&mut self.buffer)?;
+            if len == self.payload.len()
+                && self.buffer[..SEQUENCE_LEN] == self.payload[..SEQUENCE_LEN]
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_round_trip() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            // Echo a stale datagram first, which the pinger has to skip
+            let (len, peer) = server.recv_from(&mut buffer).unwrap();
+            server.send_to(&[0u8; SEQUENCE_LEN], peer).unwrap();
+            server.send_to(&buffer[..len], peer).unwrap();
+        });
+
+        let mut pinger = UdpPinger::new(addr, 64, Duration::from_secs(5)).unwrap();
+        assert_eq!(pinger.payload_size(), 64);
+        pinger.ping().unwrap();
+
+        // Nothing echoes the second datagram, so it times out
+        let mut pinger = UdpPinger::new(addr, 1, Duration::from_millis(50)).unwrap();
+        assert_eq!(pinger.payload_size(), SEQUENCE_LEN);
+        assert!(pinger.ping().is_err());
+    }
+}
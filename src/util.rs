@@ -1,8 +1,20 @@
-use rand::{thread_rng, Rng};
+use clap::Args;
+use futures::stream::{self, StreamExt};
+use log::{debug, LevelFilter};
 use reqwest::Url;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::env;
-use std::hint::black_box;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
 use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 
 pub struct ServerExe {
@@ -14,7 +26,7 @@ impl ServerExe {
     pub fn format_req_url(self: &ServerExe, path: &str) -> Url {
         let mut url = Url::parse("http://localhost/").expect("Failed to parse url");
         url.set_port(Some(self.port)).expect("Failed to set port");
-        url.set_path(&path.to_string());
+        url.set_path(path);
         url
     }
 }
@@ -29,23 +41,110 @@ impl Drop for ServerExe {
     }
 }
 
+/// Printed by the HTTP and UDP servers, followed by the port they listen on, so a server
+/// started on port 0 can be found.
+pub const LISTENING_PREFIX: &str = "Listening on port ";
+
+/// Printed by the HTTP and UDP servers once requests sent to them will be answered.
+pub const READY_LINE: &str = "Ready";
+
+/// How long a spawned server has to print `READY_LINE`.
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub fn run_this_exe_as_server() -> ServerExe {
+    run_this_exe(&["server", "http://localhost:0/"])
+}
+
+/// Like `run_this_exe_as_server`, but starts the UDP echo server on `127.0.0.1`.
+pub fn run_this_exe_as_udp_server() -> ServerExe {
+    run_this_exe(&["udp", "server", "127.0.0.1:0"])
+}
+
+/// Spawns this executable as a server on port 0 and reads the port the OS gave it from its
+/// output, so that its port can't already be taken. Returns once the server reports it is
+/// ready, rather than after a fixed wait that a loaded machine may overrun.
+fn run_this_exe(args: &[&str]) -> ServerExe {
     let exe_path = env::current_exe().expect("Failed to get executable path");
-    let mut rng = thread_rng();
-    let port = rng.gen_range(3333..9999);
 
-    println!("Current exe {:?}", exe_path);
+    debug!("Current exe {:?}", exe_path);
+
+    // The server logs at the level this process does
+    let verbose = match log::max_level() {
+        LevelFilter::Info => Some("-v"),
+        LevelFilter::Debug => Some("-vv"),
+        LevelFilter::Trace => Some("-vvv"),
+        _ => None,
+    };
 
     // Spawn the server external process
     let mut c = Command::new(exe_path);
-    c.arg("server").arg(format!("http://localhost:{}/", port));
+    c.args(verbose).args(args);
 
-    let proc = c
+    let mut proc = c
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .expect("Failed to start server. Try running 'cargo build' to make sure it is built.");
 
+    // Read on a thread that carries on once the server is ready, as the server would block on a
+    // full pipe or fail writing to a closed one
+    let (tx, rx) = mpsc::channel();
+    let stdout = proc.stdout.take().expect("stdout is piped");
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let _ = tx.send(line);
+        }
+    });
+
+    // The server logs to stderr, which is passed on to this process' stderr. What it logs
+    // before it's ready is held back to explain the failure should it never get there.
+    let early_log = Arc::new(Mutex::new(Some(String::new())));
+    let stderr = proc.stderr.take().expect("stderr is piped");
+    let stderr_reader = {
+        let early_log = early_log.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                match early_log.lock().unwrap().as_mut() {
+                    Some(early) => {
+                        early.push_str(&line);
+                        early.push('\n');
+                    }
+                    None => eprintln!("{line}"),
+                }
+            }
+        })
+    };
+
+    let deadline = Instant::now() + SERVER_START_TIMEOUT;
+    let mut port = None;
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) if line == READY_LINE => {
+                if let Some(early) = early_log.lock().unwrap().take() {
+                    eprint!("{early}");
+                }
+                break;
+            }
+            Ok(line) => {
+                if let Some(listening) = line.strip_prefix(LISTENING_PREFIX) {
+                    port = listening.trim().parse().ok();
+                }
+            }
+            Err(e) => {
+                let _ = proc.kill();
+                let _ = proc.wait();
+                let _ = stderr_reader.join();
+                let error = early_log.lock().unwrap().take().unwrap_or_default();
+                let reason = match e {
+                    RecvTimeoutError::Timeout => "wasn't ready in time",
+                    RecvTimeoutError::Disconnected => "exited before it was ready",
+                };
+                panic!("Server {reason}: {}", error.trim());
+            }
+        }
+    }
+    let port = port.expect("Server was ready without reporting its port");
+
     ServerExe {
         proc: Some(proc),
         port,
@@ -54,71 +153,1228 @@ pub fn run_this_exe_as_server() -> ServerExe {
 
 pub struct LatencyMeasurement {
     pub latency: Duration,
+    /// Calls measured, including the failed ones.
+    pub iterations: usize,
+    /// Measured calls that failed. Their timings are left out of every latency statistic, as a
+    /// fast error from a down server would otherwise look like a fast response.
+    pub failures: usize,
+    pub requests_per_second: f64,
+    pub min: Duration,
+    pub max: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub std_dev: Duration,
+    /// Bounds of the 95% confidence interval for the mean latency.
+    pub ci_low: Duration,
+    pub ci_high: Duration,
+    /// Coefficient of variation, `std_dev / latency`. Zero when there are no samples.
+    pub cv: f64,
+    /// The samples that survived outlier rejection, in the order they were measured.
+    pub samples: Vec<Duration>,
+    /// Timings of the warmup calls, which are left out of every statistic above. With several
+    /// workers each worker's warmups follow the previous worker's.
+    pub warmup: Vec<Duration>,
+    /// Every measured call that succeeded, including those dropped as outliers, in the order
+    /// they started, for seeing how latency moved over the run.
+    pub timeline: Vec<TimedSample>,
+}
+
+/// One measured call: when it started, counted from the first measured call, and how long it
+/// took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedSample {
+    pub at: Duration,
+    pub latency: Duration,
 }
 
-pub fn measure_latency<F, T>(f: F) -> LatencyMeasurement
+impl LatencyMeasurement {
+    /// Fraction of the measured calls that failed, zero when nothing was measured.
+    pub fn failure_rate(&self) -> f64 {
+        if self.iterations == 0 {
+            0.0
+        } else {
+            self.failures as f64 / self.iterations as f64
+        }
+    }
+}
+
+/// What a measured closure returns. By default the whole call is timed, but an outcome can
+/// report its own latency, e.g. only the final attempt of a retried request. A failed outcome
+/// is counted but not timed.
+pub trait Outcome {
+    fn latency(&self) -> Option<Duration> {
+        None
+    }
+
+    fn failed(&self) -> bool {
+        false
+    }
+}
+
+impl Outcome for () {}
+
+impl<T, E> Outcome for Result<T, E> {
+    fn failed(&self) -> bool {
+        self.is_err()
+    }
+}
+
+/// Times one call, returning `None` when it failed.
+fn time_call<F, T>(f: &F) -> Option<Duration>
 where
     F: Fn() -> T,
+    T: Outcome,
 {
-    const MIN_ITERATIONS: usize = 10;
-    const MAX_ITERATIONS: usize = 200; // Maximum number of iterations to prevent infinite loops
-    const STABLE_THRESHOLD: f64 = 1.0; // 100% change considered stable
-    const OUTLIER_THRESHOLD: f64 = 2.0; // standard deviations away considered an outlier
+    let start = Instant::now();
+    let outcome = f();
+    timing(&outcome, start)
+}
 
-    // warm up    
-    for _ in 0..5 {
-        let _ = f();
+fn timing<T: Outcome>(outcome: &T, start: Instant) -> Option<Duration> {
+    if outcome.failed() {
+        None
+    } else {
+        Some(outcome.latency().unwrap_or_else(|| start.elapsed()))
     }
+}
 
-    let mut durations = Vec::new();
+pub struct RetryPolicy {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each retry after that.
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// How long to wait before the retry that follows `retries` earlier ones.
+    fn delay(&self, retries: u32) -> Duration {
+        self.backoff.saturating_mul(1 << retries.min(16))
+    }
+}
+
+/// The result of the last attempt made by `with_retries`.
+pub struct Retried<T, E> {
+    pub result: Result<T, E>,
+    pub retries: u32,
+    pub attempt_latency: Duration,
+}
+
+impl<T, E> Outcome for Retried<T, E> {
+    fn latency(&self) -> Option<Duration> {
+        Some(self.attempt_latency)
+    }
 
-    for i in 0..MAX_ITERATIONS {
+    fn failed(&self) -> bool {
+        self.result.is_err()
+    }
+}
+
+/// Calls `f` until it succeeds or the policy's retries are used up, sleeping with exponential
+/// backoff in between. Only the final attempt is timed, so backoff never shows up as latency.
+pub fn with_retries<T, E, F>(policy: &RetryPolicy, f: F) -> Retried<T, E>
+where
+    F: Fn() -> Result<T, E>,
+{
+    let mut retries = 0;
+
+    loop {
         let start = Instant::now();
-        let _ = f();
-        let duration = start.elapsed();
-        durations.push(duration.as_secs_f64()); 
-        
+        let result = f();
+        let attempt_latency = start.elapsed();
+
+        if result.is_ok() || retries >= policy.retries {
+            return Retried {
+                result,
+                retries,
+                attempt_latency,
+            };
+        }
+
+        thread::sleep(policy.delay(retries));
+        retries += 1;
+    }
+}
+
+/// Async form of `with_retries`, sleeping on the tokio timer instead of blocking the thread.
+pub async fn with_retries_async<T, E, F, Fut>(policy: &RetryPolicy, f: F) -> Retried<T, E>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut retries = 0;
+
+    loop {
+        let start = Instant::now();
+        let result = f().await;
+        let attempt_latency = start.elapsed();
+
+        if result.is_ok() || retries >= policy.retries {
+            return Retried {
+                result,
+                retries,
+                attempt_latency,
+            };
+        }
+
+        tokio::time::sleep(policy.delay(retries)).await;
+        retries += 1;
+    }
+}
+
+/// Controls how long the adaptive measurement loop runs. The defaults are what the loop has
+/// always used; remote endpoints may want fewer iterations.
+#[derive(Args, Debug, Clone)]
+pub struct MeasureConfig {
+    #[arg(
+        long = "warmup",
+        default_value_t = 5,
+        help = "Calls made before measuring, left out of the results"
+    )]
+    pub warmup: usize,
+    #[arg(
+        long = "min-iters",
+        default_value_t = 10,
+        help = "Calls made before checking whether latency is stable"
+    )]
+    pub min_iterations: usize,
+    #[arg(
+        long = "max-iters",
+        default_value_t = 200,
+        help = "Most calls made when latency never stabilises"
+    )]
+    pub max_iterations: usize,
+    #[arg(
+        long = "stable-threshold",
+        default_value_t = 0.05,
+        help = "Stop once the median and p99 latency have moved by less than this fraction over the last 10 calls (example 0.05 for 5%)"
+    )]
+    pub stable_threshold: f64,
+    #[arg(
+        long = "outlier-sigma",
+        default_value_t = 2.0,
+        help = "Samples this many standard deviations from the mean are dropped"
+    )]
+    pub outlier_threshold: f64,
+    #[arg(long, help = "Also print the latency of each warmup call")]
+    pub report_warmup: bool,
+    #[arg(long, help = "Also print a histogram of the measured latencies")]
+    pub histogram: bool,
+    #[arg(
+        long,
+        help = "Stop measuring at the first failed call, including the warmups"
+    )]
+    pub fail_fast: bool,
+}
+
+impl Default for MeasureConfig {
+    fn default() -> Self {
+        MeasureConfig {
+            warmup: 5,
+            min_iterations: 10,
+            max_iterations: 200, // Maximum number of iterations to prevent infinite loops
+            stable_threshold: 0.05, // 5% drift in p50 and p99 considered stable
+            outlier_threshold: 2.0, // standard deviations away considered an outlier
+            report_warmup: false,
+            histogram: false,
+            fail_fast: false,
+        }
+    }
+}
 
-        if i >= MIN_ITERATIONS {
+/// Raw timings gathered by a single measurement loop, before they are summarised.
+struct Samples {
+    warmup: Vec<Duration>,
+    durations: Vec<f64>,
+    /// When each successful call started, with its latency, before outliers are dropped.
+    calls: Vec<(Instant, Duration)>,
+    iterations: usize,
+    failures: usize,
+    elapsed: Duration,
+}
+
+/// Orders calls by when they started, counted from the first, so those of several workers
+/// interleave as they were sent.
+fn timeline(mut calls: Vec<(Instant, Duration)>) -> Vec<TimedSample> {
+    calls.sort_by_key(|(start, _)| *start);
+    let Some(&(first, _)) = calls.first() else {
+        return Vec::new();
+    };
+    calls
+        .into_iter()
+        .map(|(start, latency)| TimedSample {
+            at: start - first,
+            latency,
+        })
+        .collect()
+}
+
+/// Nearest-rank percentile over an ascending sorted slice. Returns zero for an empty slice.
+pub fn percentile(sorted: &[f64], pct: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    let index = rank.clamp(1, sorted.len()) - 1;
+    Duration::from_secs_f64(sorted[index])
+}
+
+/// Number of consecutive checks the median and p99 must agree over before the loop stops.
+const STABLE_WINDOW: usize = 10;
+
+/// The adaptive stopping rule, kept apart from the calls being timed so that the blocking and
+/// async loops stop at the same point.
+struct AdaptiveLoop<'a> {
+    config: &'a MeasureConfig,
+    durations: Vec<f64>,
+    calls: Vec<(Instant, Duration)>,
+    iterations: usize,
+    failures: usize,
+    /// The p50 and p99 at each of the last `STABLE_WINDOW` checks.
+    recent: VecDeque<(f64, f64)>,
+    stable: bool,
+}
+
+impl<'a> AdaptiveLoop<'a> {
+    fn new(config: &'a MeasureConfig) -> Self {
+        AdaptiveLoop {
+            config,
+            durations: Vec::new(),
+            calls: Vec::new(),
+            iterations: 0,
+            failures: 0,
+            recent: VecDeque::with_capacity(STABLE_WINDOW),
+            stable: false,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.stable || self.iterations >= self.config.max_iterations
+    }
+
+    /// Records the timing of a call started at `start`, or a failure when it has none.
+    fn record(&mut self, start: Instant, duration: Option<Duration>) {
+        let i = self.iterations;
+        self.iterations += 1;
+        let Some(duration) = duration else {
+            self.failures += 1;
+            return;
+        };
+        self.durations.push(duration.as_secs_f64());
+        self.calls.push((start, duration));
+
+        if i >= self.config.min_iterations {
             // Need at least 3 measurements to calculate mean and std dev
-            let mean = durations.iter().sum::<f64>() / durations.len() as f64;
-            let variance = durations
-                .iter()
-                .map(|d| {
-                    let diff = d - mean;
-                    diff * diff
-                })
-                .sum::<f64>()
-                / durations.len() as f64;
-            let std_dev = variance.sqrt();
+            let (mean, std_dev) = mean_and_std_dev(&self.durations);
 
             // Remove outliers
-            durations.retain(|d| {
+            let outlier_threshold = self.config.outlier_threshold;
+            self.durations.retain(|d| {
                 let diff = (*d - mean).abs();
-                diff / std_dev <= OUTLIER_THRESHOLD
+                std_dev == 0.0 || diff / std_dev <= outlier_threshold
             });
 
-            if durations.len() > MIN_ITERATIONS {
-                // Check for stability
-                let is_stable = durations.iter().all(|d| {
-                    let diff = (d - mean).abs();
-                    diff / mean <= STABLE_THRESHOLD
-                });
-
-                if is_stable {
-                    break;
+            if self.durations.len() > self.config.min_iterations {
+                // Stable once both the median and the tail have stopped moving, as the mean
+                // settles long before a slow p99 does
+                let mut sorted = self.durations.clone();
+                sorted.sort_by(f64::total_cmp);
+                if self.recent.len() == STABLE_WINDOW {
+                    self.recent.pop_front();
                 }
+                self.recent.push_back((
+                    percentile(&sorted, 50.0).as_secs_f64(),
+                    percentile(&sorted, 99.0).as_secs_f64(),
+                ));
+
+                let threshold = self.config.stable_threshold;
+                self.stable = self.recent.len() == STABLE_WINDOW
+                    && relative_range(self.recent.iter().map(|r| r.0)) <= threshold
+                    && relative_range(self.recent.iter().map(|r| r.1)) <= threshold;
             }
         }
     }
 
+    fn finish(self, warmup: Vec<Duration>, elapsed: Duration) -> Samples {
+        Samples {
+            warmup,
+            durations: self.durations,
+            calls: self.calls,
+            iterations: self.iterations,
+            failures: self.failures,
+            elapsed,
+        }
+    }
+}
+
+/// Tells every worker of a `--fail-fast` measurement to stop once any of their calls fails.
+struct FailFast {
+    enabled: bool,
+    failed: AtomicBool,
+}
+
+impl FailFast {
+    fn new(config: &MeasureConfig) -> Self {
+        FailFast {
+            enabled: config.fail_fast,
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.enabled && self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Passes a call's timing through, noting a failure when it has none.
+    fn check(&self, timing: Option<Duration>) -> Option<Duration> {
+        if timing.is_none() {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+        timing
+    }
+}
+
+thread_local! {
+    /// Set while a worker thread makes its warmup calls.
+    static WARMING_UP: Cell<bool> = const { Cell::new(false) };
+}
+
+tokio::task_local! {
+    /// Whether a call made by one of the async loops is a warmup. Their calls share a thread,
+    /// so each future carries its own answer.
+    static WARMUP_CALL: bool;
+}
+
+/// Whether the call being made is one of the warmups, for a caller that reports each call as it
+/// completes, e.g. `--stream`.
+pub fn is_warmup() -> bool {
+    WARMUP_CALL
+        .try_with(|warmup| *warmup)
+        .unwrap_or_else(|_| WARMING_UP.get())
+}
+
+/// Makes the warmup calls, returning the timings of those that succeeded so they can be
+/// reported apart from the measured samples.
+fn warm_up<F, T>(config: &MeasureConfig, fail_fast: &FailFast, f: &F) -> Vec<Duration>
+where
+    F: Fn() -> T,
+    T: Outcome,
+{
+    WARMING_UP.set(true);
+    let warmup = (0..config.warmup)
+        .take_while(|_| !fail_fast.stopped())
+        .filter_map(|_| fail_fast.check(time_call(f)))
+        .collect();
+    WARMING_UP.set(false);
+    warmup
+}
+
+fn collect_samples<F, T>(config: &MeasureConfig, fail_fast: &FailFast, f: &F) -> Samples
+where
+    F: Fn() -> T,
+    T: Outcome,
+{
+    let warmup = warm_up(config, fail_fast, f);
+    let mut samples = AdaptiveLoop::new(config);
+    let loop_start = Instant::now();
+
+    while !samples.is_done() && !fail_fast.stopped() {
+        let start = Instant::now();
+        samples.record(start, fail_fast.check(time_call(f)));
+    }
+
+    samples.finish(warmup, loop_start.elapsed())
+}
+
+/// Issues requests back to back until `duration` has elapsed, keeping every sample.
+fn collect_samples_for<F, T>(
+    config: &MeasureConfig,
+    duration: Duration,
+    fail_fast: &FailFast,
+    f: &F,
+) -> Samples
+where
+    F: Fn() -> T,
+    T: Outcome,
+{
+    let warmup = warm_up(config, fail_fast, f);
+    let mut calls = Vec::new();
+    let mut failures = 0;
+    let loop_start = Instant::now();
+
+    while loop_start.elapsed() < duration && !fail_fast.stopped() {
+        let start = Instant::now();
+        match fail_fast.check(time_call(f)) {
+            Some(latency) => calls.push((start, latency)),
+            None => failures += 1,
+        }
+    }
+
+    Samples {
+        warmup,
+        iterations: calls.len() + failures,
+        durations: calls
+            .iter()
+            .map(|(_, latency)| latency.as_secs_f64())
+            .collect(),
+        calls,
+        failures,
+        elapsed: loop_start.elapsed(),
+    }
+}
+
+/// Issues requests until `count` have been claimed from `claimed`, which is shared by every
+/// worker so that exactly `count` are sent between them, keeping every sample.
+fn collect_samples_count<F, T>(
+    config: &MeasureConfig,
+    count: usize,
+    claimed: &AtomicUsize,
+    fail_fast: &FailFast,
+    f: &F,
+) -> Samples
+where
+    F: Fn() -> T,
+    T: Outcome,
+{
+    let warmup = warm_up(config, fail_fast, f);
+    let mut calls = Vec::new();
+    let mut failures = 0;
+    let loop_start = Instant::now();
+
+    while !fail_fast.stopped() && claimed.fetch_add(1, Ordering::Relaxed) < count {
+        let start = Instant::now();
+        match fail_fast.check(time_call(f)) {
+            Some(latency) => calls.push((start, latency)),
+            None => failures += 1,
+        }
+    }
+
+    Samples {
+        warmup,
+        iterations: calls.len() + failures,
+        durations: calls
+            .iter()
+            .map(|(_, latency)| latency.as_secs_f64())
+            .collect(),
+        calls,
+        failures,
+        elapsed: loop_start.elapsed(),
+    }
+}
+
+/// Spread of the values relative to the smallest, zero when they are all zero.
+fn relative_range(values: impl Iterator<Item = f64>) -> f64 {
+    let (min, max) = values.fold((f64::MAX, 0.0f64), |(min, max), v| (min.min(v), max.max(v)));
+    if max == 0.0 {
+        0.0
+    } else {
+        (max - min) / min
+    }
+}
+
+/// Population mean and standard deviation, both zero for an empty slice.
+fn mean_and_std_dev(durations: &[f64]) -> (f64, f64) {
+    if durations.is_empty() {
+        return (0.0, 0.0);
+    }
+
     let mean = durations.iter().sum::<f64>() / durations.len() as f64;
+    let variance = durations
+        .iter()
+        .map(|d| {
+            let diff = d - mean;
+            diff * diff
+        })
+        .sum::<f64>()
+        / durations.len() as f64;
+
+    (mean, variance.sqrt())
+}
+
+/// Two-sided 95% critical values of Student's t distribution for 1 to 30 degrees of freedom.
+const T_CRITICAL_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// Half the width of the 95% confidence interval for the mean of `count` samples whose
+/// population standard deviation is `std_dev`.
+///
+/// Small samples widen the interval twice over: the standard error shrinks only with the square
+/// root of the count, and with few samples the t distribution's heavier tails need a larger
+/// multiplier, e.g. 2.26 for 10 samples against the 1.96 of the normal approximation used past
+/// 30. A single sample gives no estimate of the spread, so its margin is zero.
+fn confidence_margin(std_dev: f64, count: usize) -> f64 {
+    if count < 2 {
+        return 0.0;
+    }
+
+    let n = count as f64;
+    let sample_std_dev = std_dev * (n / (n - 1.0)).sqrt();
+    let standard_error = sample_std_dev / n.sqrt();
+    let t = T_CRITICAL_95.get(count - 2).copied().unwrap_or(1.96);
+    t * standard_error
+}
+
+fn summarize(
+    warmup: Vec<Duration>,
+    durations: Vec<f64>,
+    timeline: Vec<TimedSample>,
+    iterations: usize,
+    failures: usize,
+    requests_per_second: f64,
+) -> LatencyMeasurement {
+    let (mean, std_dev) = mean_and_std_dev(&durations);
+    let cv = if mean == 0.0 { 0.0 } else { std_dev / mean };
+    let margin = confidence_margin(std_dev, durations.len());
+
+    let samples = durations
+        .iter()
+        .map(|d| Duration::from_secs_f64(*d))
+        .collect();
+    let mut durations = durations;
+    durations.sort_by(|a, b| a.total_cmp(b));
 
     LatencyMeasurement {
-        latency : Duration::from_secs_f64(mean),
+        latency: Duration::from_secs_f64(mean),
+        iterations,
+        failures,
+        requests_per_second,
+        min: percentile(&durations, 0.0),
+        max: percentile(&durations, 100.0),
+        p50: percentile(&durations, 50.0),
+        p90: percentile(&durations, 90.0),
+        p99: percentile(&durations, 99.0),
+        std_dev: Duration::from_secs_f64(std_dev),
+        ci_low: Duration::from_secs_f64((mean - margin).max(0.0)),
+        ci_high: Duration::from_secs_f64(mean + margin),
+        cv,
+        samples,
+        warmup,
+        timeline,
+    }
+}
+
+/// Summarises samples gathered elsewhere, e.g. read back from a file, keeping all of them.
+/// `failures` are calls with no latency, and `elapsed` is the time the calls were spread over,
+/// from which the request rate is taken.
+pub fn summarize_samples(
+    samples: &[Duration],
+    failures: usize,
+    elapsed: Duration,
+) -> LatencyMeasurement {
+    let iterations = samples.len() + failures;
+    summarize(
+        Vec::new(),
+        samples.iter().map(Duration::as_secs_f64).collect(),
+        Vec::new(),
+        iterations,
+        failures,
+        rate(iterations, elapsed),
+    )
+}
+
+fn rate(iterations: usize, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        iterations as f64 / elapsed.as_secs_f64()
     }
 }
 
+/// Runs the adaptive measurement loop, awaiting each call in turn on the current runtime.
+/// `progress` is told how many calls have been timed so far, which is at most
+/// `config.max_iterations`.
+pub async fn measure_latency_async<F, Fut, T, P>(
+    config: &MeasureConfig,
+    f: F,
+    progress: P,
+) -> LatencyMeasurement
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = T>,
+    T: Outcome,
+    P: Fn(usize),
+{
+    let fail_fast = FailFast::new(config);
+    let mut warmup = Vec::with_capacity(config.warmup);
+    for _ in 0..config.warmup {
+        if fail_fast.stopped() {
+            break;
+        }
+        let start = Instant::now();
+        let outcome = WARMUP_CALL.scope(true, f()).await;
+        warmup.extend(fail_fast.check(timing(&outcome, start)));
+    }
+
+    let mut samples = AdaptiveLoop::new(config);
+    let loop_start = Instant::now();
+
+    while !samples.is_done() && !fail_fast.stopped() {
+        let start = Instant::now();
+        let outcome = f().await;
+        samples.record(start, fail_fast.check(timing(&outcome, start)));
+        progress(samples.iterations);
+    }
+
+    let samples = samples.finish(warmup, loop_start.elapsed());
+    let requests_per_second = rate(samples.iterations, samples.elapsed);
+    summarize(
+        samples.warmup,
+        samples.durations,
+        timeline(samples.calls),
+        samples.iterations,
+        samples.failures,
+        requests_per_second,
+    )
+}
+
+/// Runs the measurement loop on `concurrency` threads at once and combines their samples.
+/// The reported request rate is the sum of each worker's rate over its own measurement window.
+pub fn measure_latency_concurrent<F, T>(
+    config: &MeasureConfig,
+    concurrency: usize,
+    f: F,
+) -> LatencyMeasurement
+where
+    F: Fn() -> T + Sync,
+    T: Outcome,
+{
+    let fail_fast = FailFast::new(config);
+    run_concurrent(concurrency, &f, |f| collect_samples(config, &fail_fast, f))
+}
+
+/// Time-bounded alternative to `measure_latency_concurrent`: every worker keeps sending until
+/// `duration` has elapsed and all samples are kept, so `iterations` is the full sample count.
+pub fn measure_latency_for<F, T>(
+    config: &MeasureConfig,
+    duration: Duration,
+    concurrency: usize,
+    f: F,
+) -> LatencyMeasurement
+where
+    F: Fn() -> T + Sync,
+    T: Outcome,
+{
+    let fail_fast = FailFast::new(config);
+    run_concurrent(concurrency, &f, |f| {
+        collect_samples_for(config, duration, &fail_fast, f)
+    })
+}
+
+/// Fixed-count alternative to `measure_latency_concurrent`: exactly `count` requests are measured
+/// across all workers, after each worker's warmups, and all samples are kept, so the number sent
+/// can be matched against what the server saw.
+pub fn measure_latency_count<F, T>(
+    config: &MeasureConfig,
+    count: usize,
+    concurrency: usize,
+    f: F,
+) -> LatencyMeasurement
+where
+    F: Fn() -> T + Sync,
+    T: Outcome,
+{
+    let claimed = AtomicUsize::new(0);
+    let fail_fast = FailFast::new(config);
+    run_concurrent(concurrency, &f, |f| {
+        collect_samples_count(config, count, &claimed, &fail_fast, f)
+    })
+}
+
+fn run_concurrent<F, T, C>(concurrency: usize, f: &F, collect: C) -> LatencyMeasurement
+where
+    F: Fn() -> T + Sync,
+    T: Outcome,
+    C: Fn(&F) -> Samples + Sync,
+{
+    let results: Vec<Samples> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency.max(1))
+            .map(|_| scope.spawn(|| collect(f)))
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("measurement worker panicked"))
+            .collect()
+    });
+
+    let iterations = results.iter().map(|r| r.iterations).sum();
+    let failures = results.iter().map(|r| r.failures).sum();
+    let requests_per_second = results.iter().map(|r| rate(r.iterations, r.elapsed)).sum();
+    let mut warmup = Vec::new();
+    let mut durations = Vec::new();
+    let mut calls = Vec::new();
+    for result in results {
+        warmup.extend(result.warmup);
+        durations.extend(result.durations);
+        calls.extend(result.calls);
+    }
+
+    summarize(
+        warmup,
+        durations,
+        timeline(calls),
+        iterations,
+        failures,
+        requests_per_second,
+    )
+}
+
+/// Result of `measure_latency_at_rate`: how long requests took once sent, and how long each
+/// waited past its due time before it could be sent.
+pub struct RateMeasurement {
+    pub service: LatencyMeasurement,
+    pub queueing: LatencyMeasurement,
+}
+
+/// Slowest rate `measure_latency_at_rate` accepts, one request every 1000 seconds. Far slower
+/// rates would space requests further apart than a `Duration` can hold.
+pub const MIN_RATE: f64 = 0.001;
+
+/// Open-loop alternative to the loops above, sending at a fixed `target_rate` per second instead of
+/// as fast as responses come back. Request `i` is due `i / target_rate` seconds after the start
+/// whether or not earlier ones have finished, and at most `max_in_flight` are outstanding. A
+/// request due while they are all busy waits; that wait is its queueing delay, kept apart
+/// from the service latency timed from when it was actually sent, so a server that can't keep
+/// up shows as growing queueing rather than being hidden by the client slowing down.
+///
+/// Requests are sent for `duration`, or `config.max_iterations` are sent, after the warmups.
+/// Every sample is kept. tokio's timer has millisecond resolution, so requests may go out up to
+/// a millisecond late, and above 1000 per second they go in a burst each millisecond.
+pub async fn measure_latency_at_rate<F, Fut, T>(
+    config: &MeasureConfig,
+    target_rate: f64,
+    duration: Option<Duration>,
+    max_in_flight: usize,
+    f: F,
+) -> RateMeasurement
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = T>,
+    T: Outcome,
+{
+    let measured = duration.map_or(config.max_iterations, |duration| {
+        (duration.as_secs_f64() * target_rate).ceil() as usize
+    });
+    let interval = Duration::from_secs_f64(1.0 / target_rate);
+    let start = tokio::time::Instant::now();
+    let f = &f;
+    let fail_fast = &FailFast::new(config);
+
+    // Each request's future is only created once a slot is free, so a late start shows up
+    // as queueing. Requests due after a failure with `--fail-fast` aren't sent at all
+    let mut results: Vec<(usize, Duration, Instant, Option<Duration>)> =
+        stream::iter(0..config.warmup.saturating_add(measured.max(1)))
+            .take_while(|_| std::future::ready(!fail_fast.stopped()))
+            .map(|i| async move {
+                // Timed from getting a slot rather than from waking, so the timer's rounding
+                // up to the next millisecond isn't taken for queueing
+                let due = start + interval.mul_f64(i as f64);
+                let queueing = due.elapsed();
+                tokio::time::sleep_until(due).await;
+                if fail_fast.stopped() {
+                    return None;
+                }
+                let sent = Instant::now();
+                let outcome = WARMUP_CALL.scope(i < config.warmup, f()).await;
+                Some((i, queueing, sent, fail_fast.check(timing(&outcome, sent))))
+            })
+            .buffer_unordered(max_in_flight.max(1))
+            .filter_map(|result| async move { result })
+            .collect()
+            .await;
+    let end = Instant::now();
+    results.sort_by_key(|(i, ..)| *i);
+
+    let warmups = results
+        .iter()
+        .take_while(|(i, ..)| *i < config.warmup)
+        .count();
+    let (warmup, measured) = results.split_at(warmups);
+    let warmup = warmup.iter().filter_map(|(.., latency)| *latency).collect();
+    let calls: Vec<(Instant, Duration)> = measured
+        .iter()
+        .filter_map(|(_, _, sent, latency)| latency.map(|l| (*sent, l)))
+        .collect();
+    let durations = calls
+        .iter()
+        .map(|(_, latency)| latency.as_secs_f64())
+        .collect();
+    let queueing = measured.iter().map(|(_, q, ..)| q.as_secs_f64()).collect();
+    let iterations = measured.len();
+    let failures = iterations - calls.len();
+    // Over the measured requests alone, from the first of them being sent, as the other loops
+    // leave out the warmups too
+    let measured_start = measured.iter().map(|(_, _, sent, _)| *sent).min();
+    let requests_per_second = measured_start.map_or(0.0, |measured_start| {
+        rate(iterations, end.duration_since(measured_start))
+    });
+
+    RateMeasurement {
+        service: summarize(
+            warmup,
+            durations,
+            timeline(calls),
+            iterations,
+            failures,
+            requests_per_second,
+        ),
+        queueing: summarize(
+            Vec::new(),
+            queueing,
+            Vec::new(),
+            iterations,
+            0,
+            requests_per_second,
+        ),
+    }
+}
+
+/// The first call of a measurement to fail, so `--fail-fast` can report which it was and why.
+/// Calls are only numbered when they are made one at a time, as with several workers the order
+/// they finish in neither matches the order they were sent in nor tells warmups apart.
+#[derive(Default)]
+pub struct FirstFailure {
+    calls: AtomicUsize,
+    first: Mutex<Option<(usize, String)>>,
+    concurrent: bool,
+}
+
+impl FirstFailure {
+    /// Tracks the first failure of calls made by `concurrency` workers at once.
+    pub fn new(concurrency: usize) -> Self {
+        FirstFailure {
+            concurrent: concurrency > 1,
+            ..FirstFailure::default()
+        }
+    }
+
+    /// Counts a call, keeping its error when no earlier call has failed.
+    pub fn record<T, E: fmt::Display>(&self, result: &Result<T, E>) {
+        let call = self.calls.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = result {
+            let mut first = self.first.lock().unwrap_or_else(|e| e.into_inner());
+            if first.as_ref().is_none_or(|(earliest, _)| call < *earliest) {
+                *first = Some((call, e.to_string()));
+            }
+        }
+    }
+
+    /// Describes the first failure, numbering the calls after the first `warmups` as measured
+    /// iterations from 1 when the calls were made one at a time.
+    pub fn describe(&self, warmups: usize) -> Option<String> {
+        let first = self.first.lock().unwrap_or_else(|e| e.into_inner());
+        let (call, error) = first.as_ref()?;
+        Some(if self.concurrent {
+            format!("A request failed: {error}")
+        } else if *call < warmups {
+            format!("Warmup request {} failed: {error}", call + 1)
+        } else {
+            format!("Request {} failed: {error}", call - warmups + 1)
+        })
+    }
+
+    /// Prints the first failure on stderr. Returns whether any call failed.
+    pub fn report(&self, warmups: usize) -> bool {
+        match self.describe(warmups) {
+            Some(failure) => {
+                eprintln!("{failure}");
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Makes `concurrency` calls at the same moment so that a client's pool opens a connection for
+/// each worker before any is measured. This is separate from the warmup iterations, which each
+/// worker makes one after another and so may keep reusing the same few connections.
+pub fn warm_connections<F, T>(concurrency: usize, f: &F)
+where
+    F: Fn() -> T + Sync,
+{
+    let barrier = Barrier::new(concurrency.max(1));
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| {
+                barrier.wait();
+                f();
+            });
+        }
+    });
+}
+
+/// Pools the samples of several runs of the same benchmark into one measurement, as if they
+/// had been measured in a single run. Requests per second is averaged across the runs, and
+/// each run's timeline follows straight on from the end of the previous one.
+pub fn combine_runs(runs: &[LatencyMeasurement]) -> LatencyMeasurement {
+    let iterations = runs.iter().map(|r| r.iterations).sum();
+    let failures = runs.iter().map(|r| r.failures).sum();
+    let requests_per_second = if runs.is_empty() {
+        0.0
+    } else {
+        runs.iter().map(|r| r.requests_per_second).sum::<f64>() / runs.len() as f64
+    };
+
+    let mut timeline = Vec::new();
+    let mut offset = Duration::ZERO;
+    for run in runs {
+        timeline.extend(run.timeline.iter().map(|call| TimedSample {
+            at: offset + call.at,
+            ..*call
+        }));
+        if let Some(last) = timeline.last() {
+            offset = last.at + last.latency;
+        }
+    }
+
+    summarize(
+        runs.iter().flat_map(|r| r.warmup.iter().copied()).collect(),
+        runs.iter()
+            .flat_map(|r| r.samples.iter().map(|d| d.as_secs_f64()))
+            .collect(),
+        timeline,
+        iterations,
+        failures,
+        requests_per_second,
+    )
+}
+
+/// Prints each run's average latency, then the mean of those averages and how much they
+/// varied from run to run.
+pub fn print_runs(runs: &[LatencyMeasurement]) {
+    for (run, result) in runs.iter().enumerate() {
+        println!("Run {}: average latency {:?}", run + 1, result.latency);
+    }
+
+    let means: Vec<f64> = runs.iter().map(|r| r.latency.as_secs_f64()).collect();
+    let (mean, std_dev) = mean_and_std_dev(&means);
+    println!(
+        "Mean of run averages: {:?} (std dev {:?} across {} runs)",
+        Duration::from_secs_f64(mean),
+        Duration::from_secs_f64(std_dev),
+        runs.len()
+    );
+}
+
+/// Prints the latencies of several measurements of one endpoint as columns of a table, with
+/// how the last differs from the first when there are two.
+pub fn print_side_by_side(series: &[(&str, LatencyMeasurement)]) {
+    type Value = fn(&LatencyMeasurement) -> f64;
+    type Format = fn(f64) -> String;
+    let latency: Format = |secs| format!("{:?}", Duration::from_secs_f64(secs));
+    let rate: Format = |per_sec| format!("{per_sec:.1}");
+    let rows: [(&str, Value, Format); 7] = [
+        ("Mean", |result| result.latency.as_secs_f64(), latency),
+        ("Min", |result| result.min.as_secs_f64(), latency),
+        ("p50", |result| result.p50.as_secs_f64(), latency),
+        ("p90", |result| result.p90.as_secs_f64(), latency),
+        ("p99", |result| result.p99.as_secs_f64(), latency),
+        ("Max", |result| result.max.as_secs_f64(), latency),
+        ("Requests/s", |result| result.requests_per_second, rate),
+    ];
+
+    let mut header = format!("{:<12}", "");
+    for (name, _) in series {
+        header.push_str(&format!(" {name:>16}"));
+    }
+    if series.len() == 2 {
+        header.push_str(&format!(" {:>9}", "Change"));
+    }
+    println!("{header}");
+
+    for (label, value, format) in rows {
+        let mut row = format!("{label:<12}");
+        for (_, result) in series {
+            row.push_str(&format!(" {:>16}", format(value(result))));
+        }
+        if let [(_, first), (_, last)] = series {
+            let (first, last) = (value(first), value(last));
+            if first > 0.0 {
+                row.push_str(&format!(" {:>+8.1}%", (last - first) / first * 100.0));
+            }
+        }
+        println!("{row}");
+    }
+}
+
+/// Writes every retained sample as `iteration,payload_size,latency_ns`, one measurement per
+/// payload size. The header row is written even when there are no samples.
+pub fn write_csv(path: &Path, runs: &[(u64, &LatencyMeasurement)]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "iteration,payload_size,latency_ns")?;
+
+    for (payload_size, measurement) in runs {
+        for (iteration, sample) in measurement.samples.iter().enumerate() {
+            writeln!(out, "{},{},{}", iteration, payload_size, sample.as_nanos())?;
+        }
+    }
+
+    out.flush()
+}
+
 pub fn print_latency(result: &LatencyMeasurement) {
-    println!("Average latency: {:?}", result.latency);
+    println!(
+        "Average latency: {:?} ± {:?} (95% CI)",
+        result.latency,
+        result.ci_high.saturating_sub(result.latency)
+    );
+    println!("Std dev: {:?}", result.std_dev);
+    println!("Coefficient of variation: {:.3}", result.cv);
+    println!("Min latency: {:?}", result.min);
+    println!("p50 latency: {:?}", result.p50);
+    println!("p90 latency: {:?}", result.p90);
+    println!("p99 latency: {:?}", result.p99);
+    println!("Max latency: {:?}", result.max);
+    println!("Requests per second: {:.1}", result.requests_per_second);
+    if result.failures > 0 {
+        println!(
+            "Failure rate: {:.1}% ({} of {} measured calls, left out of the latencies)",
+            result.failure_rate() * 100.0,
+            result.failures,
+            result.iterations
+        );
+    }
+}
+
+/// Prints how long requests sent at a fixed rate waited past their due time.
+pub fn print_queueing(queueing: &LatencyMeasurement) {
+    println!(
+        "Queueing delay: mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+        queueing.latency, queueing.p50, queueing.p99, queueing.max
+    );
+}
+
+/// Number of bars `print_histogram` draws.
+const HISTOGRAM_BINS: usize = 20;
+/// Width in characters of the longest histogram bar.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Counts the samples falling into each of `bins` equal-width bins between the fastest and
+/// slowest sample, the slowest landing in the last bin. All the samples are in the first bin
+/// when they are equal.
+pub fn histogram(samples: &[Duration], bins: usize) -> Vec<usize> {
+    let mut counts = vec![0; bins.max(1)];
+    let (Some(min), Some(max)) = (samples.iter().min(), samples.iter().max()) else {
+        return counts;
+    };
+
+    let range = (*max - *min).as_secs_f64();
+    for sample in samples {
+        let offset = (*sample - *min).as_secs_f64();
+        let bin = if range == 0.0 {
+            0
+        } else {
+            ((offset / range) * counts.len() as f64) as usize
+        };
+        let last = counts.len() - 1;
+        counts[bin.min(last)] += 1;
+    }
+    counts
+}
+
+/// Prints a bar per histogram bin, labelled with the bin's lower bound and its count, so that
+/// e.g. a bimodal distribution stands out where the percentiles would hide it.
+pub fn print_histogram(result: &LatencyMeasurement) {
+    let counts = histogram(&result.samples, HISTOGRAM_BINS);
+    let most = counts.iter().copied().max().unwrap_or(0).max(1);
+    let width = (result.max - result.min) / counts.len() as u32;
+
+    println!("Latency histogram:");
+    for (bin, count) in counts.iter().enumerate() {
+        let start = result.min + width * bin as u32;
+        let bar = "#".repeat(count * HISTOGRAM_WIDTH / most);
+        let start = format!("{:.1?}", start);
+        println!("{start:>12} | {bar:<HISTOGRAM_WIDTH$} {count}");
+    }
+}
+
+/// Prints each warmup latency in the order the calls were made.
+pub fn print_warmup(result: &LatencyMeasurement) {
+    let latencies: Vec<String> = result.warmup.iter().map(|d| format!("{:?}", d)).collect();
+    println!("Warmup latencies: {}", latencies.join(", "));
+}
+
+pub fn format_size(size_in_bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = KB * 1024;
+
+    if size_in_bytes >= MB {
+        format!("{:.1}mb", size_in_bytes as f64 / MB as f64)
+    } else if size_in_bytes >= KB {
+        format!("{:.1}kb", size_in_bytes as f64 / KB as f64)
+    } else {
+        format!("{}b", size_in_bytes)
+    }
+}
+
+/// Payload bytes moved per second, in MB/s using the same 1024 based units as `format_size`.
+pub fn throughput_mb_per_sec(size_in_bytes: u64, latency: Duration) -> f64 {
+    const MB: f64 = 1024.0 * 1024.0;
+
+    if latency.is_zero() {
+        0.0
+    } else {
+        size_in_bytes as f64 / MB / latency.as_secs_f64()
+    }
+}
+
+pub fn format_throughput(mb_per_sec: f64) -> String {
+    format!("{:.1} MB/s", mb_per_sec)
+}
+
+/// Inverse of `format_size`, accepting plain byte counts or `b`, `kb` and `mb` suffixes.
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    const KB: f64 = 1024.0;
+    const MB: f64 = KB * 1024.0;
+
+    let lower = size.trim().to_ascii_lowercase();
+    let (number, scale) = if let Some(n) = lower.strip_suffix("mb") {
+        (n, MB)
+    } else if let Some(n) = lower.strip_suffix("kb") {
+        (n, KB)
+    } else if let Some(n) = lower.strip_suffix('b') {
+        (n, 1.0)
+    } else {
+        (lower.as_str(), 1.0)
+    };
+
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{size}', expected a value like 64b, 64kb or 1mb"))?;
+
+    if value < 0.0 || !value.is_finite() {
+        return Err(format!("invalid size '{size}', must be a positive number"));
+    }
+
+    Ok((value * scale).round() as usize)
+}
+
+/// Parses durations such as `250ms`, `30s` or `2m`. A bare number is taken as seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let lower = duration.trim().to_ascii_lowercase();
+    let (number, scale) = if let Some(n) = lower.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = lower.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = lower.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        (lower.as_str(), 1.0)
+    };
+
+    let value: f64 = number.trim().parse().map_err(|_| {
+        format!("invalid duration '{duration}', expected a value like 500ms, 30s or 2m")
+    })?;
+
+    if value < 0.0 || !value.is_finite() {
+        return Err(format!(
+            "invalid duration '{duration}', must be a positive number"
+        ));
+    }
+
+    Duration::try_from_secs_f64(value * scale)
+        .map_err(|_| format!("invalid duration '{duration}', too long"))
 }